@@ -0,0 +1,20 @@
+#ifndef __GNUC_VA_LIST
+/* glibc's <stdio.h> declares vprintf and friends with this name */
+#define __GNUC_VA_LIST 1
+typedef __builtin_va_list __gnuc_va_list;
+#endif
+
+/* glibc's headers define __need___va_list to get only __gnuc_va_list */
+#ifdef __need___va_list
+#undef __need___va_list
+#elif !defined __STDARG_H
+#define __STDARG_H
+
+typedef __gnuc_va_list va_list;
+
+#define va_start(ap, last) __builtin_va_start(ap, last)
+#define va_end(ap) __builtin_va_end(ap)
+#define va_arg(ap, type) __builtin_va_arg(ap, type)
+#define va_copy(dest, src) __builtin_va_copy(dest, src)
+
+#endif
@@ -0,0 +1,56 @@
+/* glibc's headers define __need_size_t and the like to get only part of this header,
+   and each of them is undefined again once done */
+#if defined __need_size_t || defined __need_ptrdiff_t || defined __need_wchar_t \
+    || defined __need_wint_t || defined __need_NULL
+#define __STDDEF_PARTIAL
+#else
+#define __need_size_t
+#define __need_ptrdiff_t
+#define __need_wchar_t
+#define __need_NULL
+#endif
+
+#if defined __need_size_t && !defined __SIZE_T
+#define __SIZE_T
+typedef unsigned long size_t;
+#endif
+#undef __need_size_t
+
+#if defined __need_ptrdiff_t && !defined __PTRDIFF_T
+#define __PTRDIFF_T
+typedef long ptrdiff_t;
+#endif
+#undef __need_ptrdiff_t
+
+#if defined __need_wchar_t && !defined __WCHAR_T
+#define __WCHAR_T
+typedef int wchar_t;
+#endif
+#undef __need_wchar_t
+
+#if defined __need_wint_t && !defined __WINT_T
+#define __WINT_T
+typedef unsigned int wint_t;
+#endif
+#undef __need_wint_t
+
+#ifdef __need_NULL
+#undef NULL
+#define NULL ((void *)0)
+#endif
+#undef __need_NULL
+
+#ifdef __STDDEF_PARTIAL
+#undef __STDDEF_PARTIAL
+#elif !defined __STDDEF_H
+#define __STDDEF_H
+
+/* aligned like long double, to 16 bytes, as with gcc */
+typedef struct {
+    long long __max_align_ll;
+    long double __max_align_ld;
+} max_align_t;
+
+#define offsetof(type, member) ((size_t)&(((type *)0)->member))
+
+#endif
@@ -1,25 +1,311 @@
+use crate::diagnostic::Diagnostic;
+use crate::error_code::ErrorCode;
+use crate::lexer::{IntType, Span};
 use crate::parser::{BinOpKind, Binary, Expr, ExprKind, UnOp};
+use crate::warning::Warning;
 
 pub struct Analyzer {}
 
 impl Analyzer {
+    /// Lower `expr`. Every node made has the span of the expression it stands for, and
+    /// the constants it adds have the span of the whole expression.
     pub fn down_expr(expr: Expr) -> ConvExpr {
-        match expr.kind {
+        let span = expr.span;
+        let mut conv = match expr.kind {
+            // do nothing
+            ExprKind::Num(n, ty) => ConvExpr::new_num(n, ty, span.clone()),
+            // substitute `-x` into `0-x`, where 0 has the type of `x`
+            ExprKind::Unary(UnOp::Minus, operand) => {
+                let operand = Self::down_expr(*operand);
+                ConvExpr::new_binary(
+                    BinOpKind::Sub,
+                    ConvExpr::new_num(0, operand.ty, span.clone()),
+                    operand,
+                )
+            }
+
+            // do nothing
+            ExprKind::Unary(UnOp::Plus, operand) => Self::down_expr(*operand),
+            // substitute `!x` into `x==0`
+            ExprKind::Unary(UnOp::Not, operand) => ConvExpr::new_binary(
+                BinOpKind::Eq,
+                Self::down_expr(*operand),
+                ConvExpr::new_num(0, IntType::Int, span.clone()),
+            ),
+            // substitute `~x` into `x^-1`, where -1 has the type of `x`
+            ExprKind::Unary(UnOp::BitNot, operand) => {
+                let operand = Self::down_expr(*operand);
+                let ty = operand.ty;
+                ConvExpr::new_binary(
+                    BinOpKind::BitXor,
+                    operand,
+                    ConvExpr::new_num(-1, ty, span.clone()),
+                )
+            }
+            // substitute `x&&y` into `x?y!=0:0`
+            ExprKind::Binary(Binary {
+                kind: BinOpKind::And,
+                lhs,
+                rhs,
+            }) => ConvExpr::new_cond(
+                Self::down_expr(*lhs),
+                Self::down_bool(*rhs),
+                ConvExpr::new_num(0, IntType::Int, span.clone()),
+            ),
+            // substitute `x||y` into `x?1:y!=0`
+            ExprKind::Binary(Binary {
+                kind: BinOpKind::Or,
+                lhs,
+                rhs,
+            }) => ConvExpr::new_cond(
+                Self::down_expr(*lhs),
+                ConvExpr::new_num(1, IntType::Int, span.clone()),
+                Self::down_bool(*rhs),
+            ),
             // do nothing
             ExprKind::Binary(Binary { kind, lhs, rhs }) => {
                 ConvExpr::new_binary(kind, Self::down_expr(*lhs), Self::down_expr(*rhs))
             }
             // do nothing
-            ExprKind::Num(n) => ConvExpr::new_num(n),
-            // substitute `-x` into `0-x`
-            ExprKind::Unary(UnOp::Minus, operand) => ConvExpr::new_binary(
-                BinOpKind::Sub,
-                ConvExpr::new_num(0),
-                Self::down_expr(*operand),
+            ExprKind::Cond(cond, then, els) => ConvExpr::new_cond(
+                Self::down_expr(*cond),
+                Self::down_expr(*then),
+                Self::down_expr(*els),
             ),
+        };
+        conv.span = span;
+        conv
+    }
 
-            // do nothing
-            ExprKind::Unary(UnOp::Plus, operand) => Self::down_expr(*operand),
+    /// Convert `expr` into `expr!=0`, which is 0 or 1.
+    fn down_bool(expr: Expr) -> ConvExpr {
+        let span = expr.span.clone();
+        let zero = ConvExpr::new_num(0, IntType::Int, span.clone());
+        let mut conv = ConvExpr::new_binary(BinOpKind::Ne, Self::down_expr(expr), zero);
+        conv.span = span;
+        conv
+    }
+
+    /// Warn about what is valid but likely a mistake in `expr`. Which of the warnings
+    /// are reported is up to `WarningOptions`.
+    pub fn check(expr: &Expr) -> Vec<Diagnostic> {
+        let mut warnings = Vec::new();
+        Self::check_expr(expr, &mut warnings);
+        warnings
+    }
+
+    fn check_expr(expr: &Expr, warnings: &mut Vec<Diagnostic>) {
+        let Binary { kind, lhs, rhs } = match &expr.kind {
+            ExprKind::Num(..) => return,
+            ExprKind::Unary(_, operand) => return Self::check_expr(operand, warnings),
+            ExprKind::Cond(cond, then, els) => {
+                for operand in [cond, then, els] {
+                    Self::check_expr(operand, warnings);
+                }
+                return;
+            }
+            ExprKind::Binary(binary) => binary,
+        };
+        Self::check_expr(lhs, warnings);
+        Self::check_expr(rhs, warnings);
+
+        let at_expr =
+            |warning, message: String| Diagnostic::warning(warning, expr.span.clone(), message);
+        match kind {
+            BinOpKind::Div | BinOpKind::Rem if Self::constant(rhs) == Some(0) => {
+                warnings.push(at_expr(Warning::DivByZero, "division by zero".to_string()));
+            }
+            BinOpKind::Shl | BinOpKind::Shr => {
+                let direction = if *kind == BinOpKind::Shl {
+                    "left"
+                } else {
+                    "right"
+                };
+                match Self::constant(rhs) {
+                    Some(count) if count < 0 => warnings.push(at_expr(
+                        Warning::ShiftCountNegative,
+                        format!("{} shift count is negative", direction),
+                    )),
+                    Some(count) if count as usize >= Self::type_of(lhs).width() => {
+                        warnings.push(at_expr(
+                            Warning::ShiftCountOverflow,
+                            format!("{} shift count >= width of type", direction),
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+            BinOpKind::Or => {
+                let bare: Vec<_> = [lhs, rhs]
+                    .into_iter()
+                    .filter(|operand| Self::is_bare(operand, &[BinOpKind::And]))
+                    .collect();
+                if !bare.is_empty() {
+                    let mut warning = at_expr(
+                        Warning::Parentheses,
+                        "suggest parentheses around '&&' within '||'".to_string(),
+                    );
+                    for operand in bare {
+                        warning = warning
+                            .with_fixit(operand.span.shrink_to_start(), "(")
+                            .with_fixit(operand.span.shrink_to_end(), ")");
+                    }
+                    warnings.push(warning);
+                }
+            }
+            _ => {}
+        }
+
+        let relational = [BinOpKind::Lt, BinOpKind::Le, BinOpKind::Gt, BinOpKind::Ge];
+        if relational.contains(kind) && Self::is_bare(lhs, &relational) {
+            warnings.push(at_expr(
+                Warning::Parentheses,
+                "comparisons like 'X<=Y<=Z' do not have their mathematical meaning".to_string(),
+            ));
+        }
+        if relational.contains(kind) || matches!(kind, BinOpKind::Eq | BinOpKind::Ne) {
+            let (lhs_type, rhs_type) = (Self::type_of(lhs), Self::type_of(rhs));
+            // only a negative signed operand changes value when converted to unsigned
+            let signed = if lhs_type.is_unsigned() { rhs } else { lhs };
+            if lhs_type.is_unsigned() != rhs_type.is_unsigned()
+                && lhs_type.common(&rhs_type).is_unsigned()
+                && Self::constant(signed).is_none_or(|value| value < 0)
+            {
+                warnings.push(at_expr(
+                    Warning::SignCompare,
+                    format!(
+                        "comparison of integer expressions of different signedness: '{}' and '{}'",
+                        lhs_type, rhs_type
+                    ),
+                ));
+            }
+        }
+    }
+
+    /// Whether `expr` is one of the binary operators `kinds` written without parentheses
+    /// around it. In a macro expansion every token has the span of the invocation, so it
+    /// cannot be told there and is taken to have them.
+    fn is_bare(expr: &Expr, kinds: &[BinOpKind]) -> bool {
+        match &expr.kind {
+            ExprKind::Binary(Binary { kind, lhs, .. }) if kinds.contains(kind) => {
+                expr.span.start == lhs.span.start && expr.span != lhs.span
+            }
+            _ => false,
+        }
+    }
+
+    /// The value of `expr` if it is a constant expression that can be evaluated, as the
+    /// generated code computes it.
+    fn constant(expr: &Expr) -> Option<isize> {
+        Self::eval(&Self::down_expr(expr.clone()), false).ok()
+    }
+
+    /// The type of `expr` after the integer promotions and the usual arithmetic
+    /// conversions.
+    fn type_of(expr: &Expr) -> IntType {
+        Self::down_expr(expr.clone()).ty
+    }
+
+    /// Evaluate a constant expression, as `#if` needs. Arithmetic wraps around like the
+    /// generated code does.
+    pub fn eval_expr(expr: &ConvExpr) -> Result<isize, Diagnostic> {
+        Self::eval(expr, true)
+    }
+
+    /// Evaluate a constant expression in `#if` if `in_if`, where every value is an
+    /// `intmax_t` or a `uintmax_t`, or else with the types of the compiled code. There
+    /// every value is converted to its type, as the generator does, so `int` wraps
+    /// around at 32 bits.
+    fn eval(expr: &ConvExpr, in_if: bool) -> Result<isize, Diagnostic> {
+        let convert = |value: isize, ty: IntType| match ty {
+            _ if in_if => value,
+            IntType::Int => value as i32 as isize,
+            IntType::UInt => value as u32 as isize,
+            _ => value,
+        };
+        let value = match &expr.kind {
+            ConvExprKind::Num(n) => *n,
+            ConvExprKind::Cond(cond, then, els) => {
+                if Self::eval(cond, in_if)? != 0 {
+                    Self::eval(then, in_if)?
+                } else {
+                    Self::eval(els, in_if)?
+                }
+            }
+            ConvExprKind::Binary(binary) => {
+                let ConvBinary { kind, lhs, rhs } = binary;
+                let operand_type = binary.operand_type();
+                let unsigned = if in_if {
+                    Self::is_unsigned_in_if(binary)
+                } else {
+                    operand_type.is_unsigned()
+                };
+                let lhs = convert(Self::eval(lhs, in_if)?, operand_type);
+                let rhs = match kind {
+                    BinOpKind::Shl | BinOpKind::Shr => Self::eval(rhs, in_if)?,
+                    _ => convert(Self::eval(rhs, in_if)?, operand_type),
+                };
+                let (ulhs, urhs) = (lhs as u64, rhs as u64);
+                match kind {
+                    BinOpKind::Add => lhs.wrapping_add(rhs),
+                    BinOpKind::Sub => lhs.wrapping_sub(rhs),
+                    BinOpKind::Mul => lhs.wrapping_mul(rhs),
+                    BinOpKind::Div | BinOpKind::Rem if rhs == 0 => {
+                        return Err(Diagnostic::error(
+                            ErrorCode::DivisionByZero,
+                            expr.span.clone(),
+                            "division by zero",
+                        ));
+                    }
+                    BinOpKind::Div if unsigned => (ulhs / urhs) as isize,
+                    BinOpKind::Div => lhs.wrapping_div(rhs),
+                    BinOpKind::Rem if unsigned => (ulhs % urhs) as isize,
+                    BinOpKind::Rem => lhs.wrapping_rem(rhs),
+                    BinOpKind::BitXor => lhs ^ rhs,
+                    BinOpKind::BitAnd => lhs & rhs,
+                    BinOpKind::BitOr => lhs | rhs,
+                    BinOpKind::Shl => lhs.wrapping_shl(rhs as u32),
+                    BinOpKind::Shr if unsigned => ulhs.wrapping_shr(rhs as u32) as isize,
+                    BinOpKind::Shr => lhs.wrapping_shr(rhs as u32),
+                    BinOpKind::Eq => (lhs == rhs) as isize,
+                    BinOpKind::Ne => (lhs != rhs) as isize,
+                    BinOpKind::Lt if unsigned => (ulhs < urhs) as isize,
+                    BinOpKind::Lt => (lhs < rhs) as isize,
+                    BinOpKind::Le if unsigned => (ulhs <= urhs) as isize,
+                    BinOpKind::Le => (lhs <= rhs) as isize,
+                    BinOpKind::Ge if unsigned => (ulhs >= urhs) as isize,
+                    BinOpKind::Ge => (lhs >= rhs) as isize,
+                    BinOpKind::Gt if unsigned => (ulhs > urhs) as isize,
+                    BinOpKind::Gt => (lhs > rhs) as isize,
+                    BinOpKind::And | BinOpKind::Or => unreachable!("lowered by down_expr"),
+                }
+            }
+        };
+        Ok(convert(value, expr.ty))
+    }
+
+    /// Whether `binary` is done on unsigned values in `#if`. There the signed types act
+    /// as `intmax_t` and the unsigned ones as `uintmax_t` (C11 6.10.1p4), so after the
+    /// usual arithmetic conversions an operation is unsigned if either operand is.
+    fn is_unsigned_in_if(binary: &ConvBinary) -> bool {
+        fn unsigned(expr: &ConvExpr) -> bool {
+            match &expr.kind {
+                ConvExprKind::Num(_) => expr.ty.is_unsigned(),
+                ConvExprKind::Cond(_, then, els) => unsigned(then) || unsigned(els),
+                ConvExprKind::Binary(binary) => match binary.kind {
+                    BinOpKind::Eq
+                    | BinOpKind::Ne
+                    | BinOpKind::Lt
+                    | BinOpKind::Le
+                    | BinOpKind::Ge
+                    | BinOpKind::Gt => false,
+                    _ => Analyzer::is_unsigned_in_if(binary),
+                },
+            }
+        }
+        match binary.kind {
+            BinOpKind::Shl | BinOpKind::Shr => unsigned(&binary.lhs),
+            _ => unsigned(&binary.lhs) || unsigned(&binary.rhs),
         }
     }
 }
@@ -27,17 +313,45 @@ impl Analyzer {
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct ConvExpr {
     pub kind: ConvExprKind,
+    /// The type of the value after the integer promotions and the usual arithmetic
+    /// conversions.
+    pub ty: IntType,
+    pub span: Span,
 }
 impl ConvExpr {
     pub fn new_binary(kind: BinOpKind, lhs: ConvExpr, rhs: ConvExpr) -> Self {
+        let ty = match kind {
+            BinOpKind::Shl | BinOpKind::Shr => lhs.ty,
+            BinOpKind::Eq
+            | BinOpKind::Ne
+            | BinOpKind::Lt
+            | BinOpKind::Le
+            | BinOpKind::Ge
+            | BinOpKind::Gt
+            | BinOpKind::And
+            | BinOpKind::Or => IntType::Int,
+            _ => lhs.ty.common(&rhs.ty),
+        };
         Self {
+            span: lhs.span.to(&rhs.span),
+            ty,
             kind: ConvExprKind::Binary(ConvBinary::new(kind, Box::new(lhs), Box::new(rhs))),
         }
     }
 
-    pub fn new_num(num: isize) -> Self {
+    pub fn new_num(num: isize, ty: IntType, span: Span) -> Self {
         Self {
             kind: ConvExprKind::Num(num),
+            ty,
+            span,
+        }
+    }
+
+    pub fn new_cond(cond: ConvExpr, then: ConvExpr, els: ConvExpr) -> Self {
+        Self {
+            span: cond.span.to(&els.span),
+            ty: then.ty.common(&els.ty),
+            kind: ConvExprKind::Cond(Box::new(cond), Box::new(then), Box::new(els)),
         }
     }
 }
@@ -46,6 +360,7 @@ impl ConvExpr {
 pub enum ConvExprKind {
     Binary(ConvBinary),
     Num(isize),
+    Cond(Box<ConvExpr>, Box<ConvExpr>, Box<ConvExpr>),
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -59,4 +374,151 @@ impl ConvBinary {
     pub fn new(kind: BinOpKind, lhs: Box<ConvExpr>, rhs: Box<ConvExpr>) -> Self {
         Self { kind, lhs, rhs }
     }
+
+    /// The type the operands are converted to before the operation: their common type,
+    /// or for a shift the type of the left operand alone.
+    pub fn operand_type(&self) -> IntType {
+        match self.kind {
+            BinOpKind::Shl | BinOpKind::Shr => self.lhs.ty,
+            _ => self.lhs.ty.common(&self.rhs.ty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Lexer, TokenStream};
+    use crate::parser::Parser;
+
+    /// The warnings for `input`, as (warning, message) pairs.
+    fn check(input: &str) -> Vec<(Warning, String)> {
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let expr = Parser::new()
+            .parse(&mut TokenStream::new(tokens.into_iter()))
+            .unwrap();
+        Analyzer::check(&expr)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.warning.unwrap(), diagnostic.message))
+            .collect()
+    }
+
+    fn eval(input: &str) -> isize {
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let expr = Parser::new()
+            .parse(&mut TokenStream::new(tokens.into_iter()))
+            .unwrap();
+        Analyzer::eval_expr(&Analyzer::down_expr(expr)).unwrap()
+    }
+
+    /// The value of `input` as the generated code computes it
+    fn constant(input: &str) -> Option<isize> {
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let expr = Parser::new()
+            .parse(&mut TokenStream::new(tokens.into_iter()))
+            .unwrap();
+        Analyzer::constant(&expr)
+    }
+
+    fn warnings(input: &str) -> Vec<Warning> {
+        check(input)
+            .into_iter()
+            .map(|(warning, _)| warning)
+            .collect()
+    }
+
+    #[test]
+    fn test_check() {
+        assert_eq!(warnings("1 + 2 * 3"), []);
+        assert_eq!(warnings("4 % (2 - 2)"), [Warning::DivByZero]);
+        assert_eq!(warnings("1 ? 2 : 3 / 0"), [Warning::DivByZero]);
+        assert_eq!(
+            check("1 << 32"),
+            [(
+                Warning::ShiftCountOverflow,
+                "left shift count >= width of type".to_string()
+            )]
+        );
+        assert_eq!(warnings("1L << 32"), []);
+        assert_eq!(warnings("(1 ? 1 : 1L) << 32"), []);
+        assert_eq!(warnings("1 >> -1"), [Warning::ShiftCountNegative]);
+        // the count is worked out with the 32-bit unsigned int of the generated code,
+        // not the uintmax_t of #if
+        assert_eq!(warnings("1 << (0u - 1)"), [Warning::ShiftCountOverflow]);
+        assert_eq!(warnings("1 / (65536 * 65536)"), [Warning::DivByZero]);
+    }
+
+    #[test]
+    fn test_check_parentheses() {
+        assert_eq!(warnings("1 < 2 < 3"), [Warning::Parentheses]);
+        assert_eq!(warnings("(1 < 2) < 3"), []);
+        assert_eq!(warnings("1 < (2 < 3)"), []);
+        assert_eq!(warnings("1 || 2 && 3"), [Warning::Parentheses]);
+        assert_eq!(warnings("1 && 2 || 3"), [Warning::Parentheses]);
+        assert_eq!(warnings("(1 && 2) || 3"), []);
+
+        // the fix-its put parentheses around the `&&`
+        let tokens = Lexer::new("1 || 2 && 3").tokenize().unwrap();
+        let expr = Parser::new()
+            .parse(&mut TokenStream::new(tokens.into_iter()))
+            .unwrap();
+        let fixits: Vec<_> = Analyzer::check(&expr)[0]
+            .fixits
+            .iter()
+            .map(|fixit| (fixit.span.start, fixit.replacement.clone()))
+            .collect();
+        assert_eq!(fixits, [(5, "(".to_string()), (11, ")".to_string())]);
+    }
+
+    #[test]
+    fn test_eval_expr_unsigned() {
+        assert_eq!(eval("-1 < 0u"), 0);
+        assert_eq!(eval("-1 <= 0u"), 0);
+        assert_eq!(eval("-1 > 0u"), 1);
+        assert_eq!(eval("-1 >= 0u"), 1);
+        assert_eq!(eval("-1 < 0"), 1);
+        // a signed long still becomes unsigned next to an unsigned int
+        assert_eq!(eval("-1L < 1u"), 0);
+        assert_eq!(eval("(0u - 1) / 2 > 5"), 1);
+        assert_eq!(eval("(0u - 1) / 2"), isize::MAX);
+        assert_eq!(eval("-7 / 2"), -3);
+        assert_eq!(eval("(0u - 7) % 4"), 1);
+        assert_eq!(eval("-7 % 4"), -3);
+        // only the type of the left operand matters for a shift
+        assert_eq!(eval("(0u - 1) >> 63"), 1);
+        assert_eq!(eval("-1 >> 63u"), -1);
+        assert_eq!(eval("~0u >> 60"), 15);
+        // a comparison is a signed int whatever its operands are
+        assert_eq!(eval("(1u < 2u) - 2 < 0"), 1);
+        assert_eq!(eval("(1 ? 0u : 0) - 1 > 0"), 1);
+    }
+
+    #[test]
+    fn test_constant() {
+        // int and unsigned int are 32 bits wide, unlike in #if
+        assert_eq!(constant("0u - 1"), Some(u32::MAX as isize));
+        assert_eq!(eval("0u - 1"), -1);
+        assert_eq!(constant("65536 * 65536"), Some(0));
+        assert_eq!(constant("(0u - 1) >> 31"), Some(1));
+        assert_eq!(constant("-1 < 0u"), Some(0));
+        assert_eq!(constant("(1 ? 0u : 0) - 1"), Some(u32::MAX as isize));
+        assert_eq!(constant("0ul - 1"), Some(-1));
+        assert_eq!(constant("1 / 0"), None);
+    }
+
+    #[test]
+    fn test_check_sign_compare() {
+        assert_eq!(
+            check("-1 < 1u"),
+            [(
+                Warning::SignCompare,
+                "comparison of integer expressions of different signedness: 'int' and 'unsigned int'"
+                    .to_string()
+            )]
+        );
+        // a non-negative constant keeps its value, and long can hold any unsigned int
+        assert_eq!(warnings("1 == 1u"), []);
+        assert_eq!(warnings("-1L < 1u"), []);
+        assert_eq!(warnings("-1L < 1ul"), [Warning::SignCompare]);
+    }
 }
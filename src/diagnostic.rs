@@ -0,0 +1,956 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::error_code::ErrorCode;
+use crate::json::Json;
+use crate::lexer::Span;
+use crate::source_map::SourceMap;
+use crate::warning::Warning;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in the source, reported by `main` once the pass that found it
+/// has returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    pub message: String,
+    /// Where the problem is, `None` for problems with no place in the source such as an
+    /// unreadable input file
+    pub span: Option<Span>,
+    /// Other places that explain the problem, e.g. the macro definition for a wrong
+    /// number of macro arguments
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    /// Edits to the source that fix the problem, applied by `--apply-fixits`
+    pub fixits: Vec<FixIt>,
+    /// The `-W` flag that controls this, for warnings (even once made errors)
+    pub warning: Option<Warning>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// Replace the source in `span` with `replacement`: an insertion if the span is empty,
+/// a removal if the replacement is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixIt {
+    pub span: Span,
+    pub replacement: String,
+}
+
+impl FixIt {
+    fn describe(&self, source_map: &SourceMap) -> String {
+        let old = &source_map.get(self.span.file_id).input[self.span.start..self.span.end];
+        match (old.is_empty(), self.replacement.is_empty()) {
+            (true, _) => format!("insert '{}'", self.replacement),
+            (false, true) => format!("remove '{}'", old),
+            (false, false) => format!("replace '{}' with '{}'", old, self.replacement),
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn error(
+        code: ErrorCode,
+        span: impl Into<Option<Span>>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self::new(Severity::Error, code, span.into(), message.into())
+    }
+
+    pub fn warning(
+        warning: Warning,
+        span: impl Into<Option<Span>>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            warning: Some(warning),
+            ..Self::new(
+                Severity::Warning,
+                warning.code(),
+                span.into(),
+                message.into(),
+            )
+        }
+    }
+
+    fn new(severity: Severity, code: ErrorCode, span: Option<Span>, message: String) -> Self {
+        Self {
+            severity,
+            code,
+            message,
+            span,
+            labels: Vec::new(),
+            notes: Vec::new(),
+            fixits: Vec::new(),
+            warning: None,
+        }
+    }
+
+    pub fn with_label(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Suggest replacing `span` with `replacement`. Nothing is suggested if the span
+    /// comes from a macro expansion, since its text is the invocation's and not the
+    /// one the fix-it is about.
+    pub fn with_fixit(mut self, span: Span, replacement: impl Into<String>) -> Self {
+        if span.from_macro {
+            return self;
+        }
+        self.fixits.push(FixIt {
+            span,
+            replacement: replacement.into(),
+        });
+        self
+    }
+
+    /// Render for people, in the style of rustc: the severity, code and message (with
+    /// the flag that controls a warning), the `file:line:column` of the span, and its lines
+    /// with line numbers, a line of context around them and the span underlined. The
+    /// notes follow, then each label in the same form, then the first fix-it's line as
+    /// the fix-its make it. With `color`, the parts are colored with ANSI escapes.
+    pub fn render(&self, source_map: &SourceMap, color: bool) -> String {
+        let (style, severity) = match self.severity {
+            Severity::Error => (Style::Error, "error"),
+            Severity::Warning => (Style::Warning, "warning"),
+        };
+        let flag = match (self.warning, self.severity) {
+            (Some(warning), Severity::Warning) => format!(" [-W{}]", warning.name()),
+            (Some(warning), Severity::Error) => format!(" [-Werror={}]", warning.name()),
+            (None, _) => String::new(),
+        };
+        let mut rendered = format!(
+            "{}{}\n",
+            style.paint(&format!("{}[{}]", severity, self.code), color),
+            Style::Bold.paint(&format!(": {}{}", self.message, flag), color)
+        );
+
+        // the gutter fits the largest line number shown, which is past the spans
+        let last_line = self
+            .span
+            .iter()
+            .chain(self.labels.iter().map(|label| &label.span))
+            .map(|span| source_map.position(span.file_id, span.end).n_line + 2)
+            .max()
+            .unwrap_or(0);
+        let gutter = Gutter {
+            width: last_line.to_string().len(),
+            color,
+        };
+
+        if let Some(span) = &self.span {
+            rendered.push_str(&gutter.snippet(source_map, span, style));
+        }
+        for note in &self.notes {
+            writeln!(
+                rendered,
+                "{} {} {}",
+                gutter.blank(),
+                Style::Gutter.paint("=", color),
+                Style::Bold.paint(&format!("note: {}", note), color)
+            )
+            .unwrap();
+        }
+        for label in &self.labels {
+            writeln!(
+                rendered,
+                "{}",
+                Style::Note.paint(&format!("note: {}", label.message), color)
+            )
+            .unwrap();
+            rendered.push_str(&gutter.snippet(source_map, &label.span, Style::Note));
+        }
+        if !self.fixits.is_empty() {
+            let descriptions: Vec<_> = self
+                .fixits
+                .iter()
+                .map(|fixit| fixit.describe(source_map))
+                .collect();
+            writeln!(
+                rendered,
+                "{}",
+                Style::Help.paint(&format!("help: {}", descriptions.join(", ")), color)
+            )
+            .unwrap();
+            rendered.push_str(&gutter.fixed_line(source_map, &self.fixits));
+        }
+        rendered
+    }
+}
+
+/// The parts of a rendered diagnostic that are colored
+#[derive(Debug, Clone, Copy)]
+enum Style {
+    Error,
+    Warning,
+    Note,
+    Help,
+    /// The line numbers and the lines beside them
+    Gutter,
+    Bold,
+}
+
+impl Style {
+    fn paint(self, text: &str, color: bool) -> String {
+        if !color || text.is_empty() {
+            return text.to_string();
+        }
+        let escape = match self {
+            Style::Error => "\x1b[1;31m",
+            Style::Warning => "\x1b[1;33m",
+            Style::Note => "\x1b[1;32m",
+            Style::Help => "\x1b[1;36m",
+            Style::Gutter => "\x1b[1;34m",
+            Style::Bold => "\x1b[1m",
+        };
+        format!("{}{}\x1b[0m", escape, text)
+    }
+}
+
+/// The column of line numbers on the left of a rendered diagnostic
+struct Gutter {
+    /// The number of digits of the largest line number
+    width: usize,
+    color: bool,
+}
+
+impl Gutter {
+    /// The lines of a span at most, beyond which the middle ones are left out
+    const MAX_SPAN_LINES: usize = 4;
+
+    /// The gutter of a line with no number
+    fn blank(&self) -> String {
+        " ".repeat(self.width)
+    }
+
+    fn line(&self, n_line: Option<usize>, text: &str) -> String {
+        let number = n_line.map_or(String::new(), |n_line| (n_line + 1).to_string());
+        let gutter = format!("{:>width$} |", number, width = self.width);
+        format!("{} {}", Style::Gutter.paint(&gutter, self.color), text)
+            .trim_end()
+            .to_string()
+            + "\n"
+    }
+
+    /// The location of `span` and its lines, a line of context on either side, with the
+    /// span underlined in `style`: `^` at its start and `~` under the rest.
+    fn snippet(&self, source_map: &SourceMap, span: &Span, style: Style) -> String {
+        let start = source_map.position(span.file_id, span.start);
+        let mut end = source_map.position(span.file_id, span.end);
+        // a span that ends with a newline ends on the line of the newline
+        if end.n_line > start.n_line && end.n_char == 0 {
+            end = source_map.position(span.file_id, span.end - 1);
+        }
+        let (path, line) = source_map.presumed(&start);
+        let mut snippet = format!(
+            "{}{} {}:{}:{}\n",
+            self.blank(),
+            Style::Gutter.paint("-->", self.color),
+            path.display(),
+            line,
+            start.n_char + 1
+        );
+        snippet.push_str(&self.line(None, ""));
+
+        let context = |n_line: usize| {
+            source_map
+                .line(span.file_id, n_line)
+                .filter(|line| !line.trim().is_empty())
+                .map_or(String::new(), |line| {
+                    self.line(Some(n_line), &expand_tabs(line))
+                })
+        };
+        if let Some(before) = start.n_line.checked_sub(1) {
+            snippet.push_str(&context(before));
+        }
+        for n_line in start.n_line..=end.n_line {
+            let n_lines = end.n_line - start.n_line + 1;
+            if n_lines > Self::MAX_SPAN_LINES && (start.n_line + 2..end.n_line).contains(&n_line) {
+                if n_line == start.n_line + 2 {
+                    snippet.push_str(&format!("{}\n", Style::Gutter.paint("...", self.color)));
+                }
+                continue;
+            }
+            let line = source_map.line(span.file_id, n_line).unwrap_or_default();
+            let line_start = source_map.line_start(span.file_id, n_line);
+            let underline_start = if n_line == start.n_line {
+                display_width(&line[..span.start - line_start])
+            } else {
+                display_width(&line[..line.len() - line.trim_start().len()])
+            };
+            let underline_end = if n_line == end.n_line {
+                display_width(&line[..span.end.min(line_start + line.len()) - line_start])
+            } else {
+                display_width(line)
+            };
+            let len = underline_end.saturating_sub(underline_start).max(1);
+            let underline = if n_line == start.n_line {
+                format!("^{}", "~".repeat(len - 1))
+            } else {
+                "~".repeat(len)
+            };
+            snippet.push_str(&self.line(Some(n_line), &expand_tabs(line)));
+            snippet.push_str(&self.line(
+                None,
+                &format!(
+                    "{}{}",
+                    " ".repeat(underline_start),
+                    style.paint(&underline, self.color)
+                ),
+            ));
+        }
+        snippet.push_str(&context(end.n_line + 1));
+        snippet
+    }
+
+    /// The line of the first of `fixits` with those on it applied, the inserted text
+    /// marked with `+` and replacements with `~`.
+    fn fixed_line(&self, source_map: &SourceMap, fixits: &[FixIt]) -> String {
+        let first = &fixits[0];
+        let start = source_map.position(first.span.file_id, first.span.start);
+        let line = source_map
+            .line(start.file_id, start.n_line)
+            .unwrap_or_default();
+        let line_start = source_map.line_start(start.file_id, start.n_line);
+        let in_file: Vec<_> = fixits
+            .iter()
+            .filter(|fixit| fixit.span.file_id == start.file_id)
+            .collect();
+        let (fixed, replaced) = apply_fixits_marked(line, line_start, &in_file);
+
+        let mut marks = String::new();
+        for (range, fixit) in replaced {
+            let mark = if fixit.span.start == fixit.span.end {
+                "+"
+            } else {
+                "~"
+            };
+            let column = display_width(&fixed[..range.start]);
+            let width = display_width(&fixed[..range.end]) - column;
+            marks.push_str(&" ".repeat(column.saturating_sub(display_width(&marks))));
+            marks.push_str(&mark.repeat(width));
+        }
+        let mut rendered = self.line(None, "");
+        rendered.push_str(&self.line(Some(start.n_line), &expand_tabs(&fixed)));
+        rendered.push_str(&self.line(None, &Style::Help.paint(&marks, self.color)));
+        rendered
+    }
+}
+
+/// The width of a tab stop, to which tabs are expanded when a line is shown
+const TAB_WIDTH: usize = 8;
+
+/// The columns `text` takes up on a terminal from the start of a line: tabs go to the
+/// next tab stop, East Asian wide characters take two columns and combining marks none.
+fn display_width(text: &str) -> usize {
+    text.chars().fold(0, |width, c| match c {
+        '\t' => (width / TAB_WIDTH + 1) * TAB_WIDTH,
+        c => width + char_width(c),
+    })
+}
+
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036f | 0x200b..=0x200f | 0xfe00..=0xfe0f => 0,
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff
+        | 0x20000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+/// `line` with its tabs replaced by spaces up to the next tab stop, so that it lines up
+/// with the underline below it.
+fn expand_tabs(line: &str) -> String {
+    let mut expanded = String::new();
+    let mut width = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let next = (width / TAB_WIDTH + 1) * TAB_WIDTH;
+            expanded.push_str(&" ".repeat(next - width));
+            width = next;
+        } else {
+            expanded.push(c);
+            width += char_width(c);
+        }
+    }
+    expanded
+}
+
+/// `text`, which starts at byte `offset` of its file, with the fix-its in it applied.
+/// A fix-it that overlaps one before it is left out, as it may no longer make sense.
+pub fn apply_fixits(text: &str, offset: usize, fixits: &[&FixIt]) -> String {
+    apply_fixits_marked(text, offset, fixits).0
+}
+
+/// `apply_fixits`, also returning where in the result each applied fix-it put its
+/// replacement.
+fn apply_fixits_marked<'a>(
+    text: &str,
+    offset: usize,
+    fixits: &[&'a FixIt],
+) -> (String, Vec<(Range<usize>, &'a FixIt)>) {
+    let mut fixits: Vec<_> = fixits
+        .iter()
+        .copied()
+        .filter(|fixit| offset <= fixit.span.start && fixit.span.end <= offset + text.len())
+        .collect();
+    fixits.sort_by_key(|fixit| (fixit.span.start, fixit.span.end));
+
+    let mut fixed = String::new();
+    let mut replaced = Vec::new();
+    let mut copied = offset;
+    for fixit in fixits {
+        if fixit.span.start < copied {
+            continue;
+        }
+        fixed.push_str(&text[copied - offset..fixit.span.start - offset]);
+        let start = fixed.len();
+        fixed.push_str(&fixit.replacement);
+        replaced.push((start..fixed.len(), fixit));
+        copied = fixit.span.end;
+    }
+    fixed.push_str(&text[copied - offset..]);
+    (fixed, replaced)
+}
+
+/// The one of `candidates` closest to the misspelt `name`, if one is close enough to
+/// be what was meant: at most a third of its length in edits away, and at least one.
+pub fn closest_name<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|&candidate| candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// The Levenshtein distance with transpositions of adjacent characters counted as one
+/// edit, as typos often are.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // distances[i][j] is the distance between the first i chars of a and first j of b
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = distances[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = substitution
+                .min(distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// How the diagnostics are printed, chosen with `--diagnostics-format=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiagnosticFormat {
+    /// Each diagnostic rendered for people as it is found
+    #[default]
+    Text,
+    /// One JSON array of all the diagnostics, in the layout of gcc's
+    /// `-fdiagnostics-format=json`
+    Json,
+    /// One SARIF 2.1.0 log, for code review tools
+    Sarif,
+}
+
+impl DiagnosticFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(DiagnosticFormat::Text),
+            "json" => Some(DiagnosticFormat::Json),
+            "sarif" => Some(DiagnosticFormat::Sarif),
+            _ => None,
+        }
+    }
+
+    /// All the diagnostics of a compilation in this format, the text colored if `color`
+    /// is set. The JSON and SARIF documents are written even if there are no
+    /// diagnostics, so that tools can always parse the output.
+    pub fn emit(self, diagnostics: &[Diagnostic], source_map: &SourceMap, color: bool) -> String {
+        match self {
+            DiagnosticFormat::Text => diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.render(source_map, color))
+                .collect(),
+            DiagnosticFormat::Json => {
+                let diagnostics = diagnostics
+                    .iter()
+                    .map(|diagnostic| diagnostic.to_json(source_map))
+                    .collect();
+                format!("{}\n", Json::Array(diagnostics))
+            }
+            DiagnosticFormat::Sarif => format!("{}\n", sarif_log(diagnostics, source_map)),
+        }
+    }
+}
+
+impl Diagnostic {
+    fn kind(&self) -> &'static str {
+        match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    fn to_json(&self, source_map: &SourceMap) -> Json {
+        let mut members = vec![
+            ("kind", Json::str(self.kind())),
+            ("code", Json::str(self.code.to_string())),
+            ("message", Json::str(&self.message)),
+        ];
+        if let Some(warning) = self.warning {
+            members.push(("option", Json::str(format!("-W{}", warning.name()))));
+        }
+        members.push(("locations", json_locations(source_map, self.span.as_ref())));
+
+        let labels = self
+            .labels
+            .iter()
+            .map(|label| (&label.message, Some(&label.span)));
+        let notes = self.notes.iter().map(|note| (note, None));
+        let children = labels
+            .chain(notes)
+            .map(|(message, span)| {
+                Json::Object(vec![
+                    ("kind", Json::str("note")),
+                    ("message", Json::str(message)),
+                    ("locations", json_locations(source_map, span)),
+                ])
+            })
+            .collect();
+        members.push(("children", Json::Array(children)));
+        if !self.fixits.is_empty() {
+            let fixits = self
+                .fixits
+                .iter()
+                .map(|fixit| {
+                    let span = &fixit.span;
+                    Json::Object(vec![
+                        ("start", json_point(source_map, span.file_id, span.start)),
+                        ("next", json_point(source_map, span.file_id, span.end)),
+                        ("string", Json::str(&fixit.replacement)),
+                    ])
+                })
+                .collect();
+            members.push(("fixits", Json::Array(fixits)));
+        }
+        Json::Object(members)
+    }
+
+    fn to_sarif(&self, source_map: &SourceMap) -> Json {
+        let mut members = vec![
+            ("ruleId", Json::str(self.code.to_string())),
+            ("level", Json::str(self.kind())),
+        ];
+        members.push(("message", sarif_message(&self.message)));
+        if let Some(span) = &self.span {
+            members.push((
+                "locations",
+                Json::Array(vec![Json::Object(vec![(
+                    "physicalLocation",
+                    sarif_physical_location(source_map, span),
+                )])]),
+            ));
+        }
+
+        let labels = self.labels.iter().map(|label| {
+            Json::Object(vec![
+                (
+                    "physicalLocation",
+                    sarif_physical_location(source_map, &label.span),
+                ),
+                ("message", sarif_message(&label.message)),
+            ])
+        });
+        let notes = self
+            .notes
+            .iter()
+            .map(|note| Json::Object(vec![("message", sarif_message(note))]));
+        let related: Vec<_> = labels.chain(notes).collect();
+        if !related.is_empty() {
+            members.push(("relatedLocations", Json::Array(related)));
+        }
+        if !self.fixits.is_empty() {
+            // one fix with all the changes, as they are applied together
+            let changes = self
+                .fixits
+                .iter()
+                .map(|fixit| {
+                    let replacement = Json::Object(vec![
+                        ("deletedRegion", sarif_region(source_map, &fixit.span)),
+                        ("insertedContent", sarif_message(&fixit.replacement)),
+                    ]);
+                    Json::Object(vec![
+                        (
+                            "artifactLocation",
+                            sarif_artifact_location(source_map, &fixit.span),
+                        ),
+                        ("replacements", Json::Array(vec![replacement])),
+                    ])
+                })
+                .collect();
+            members.push((
+                "fixes",
+                Json::Array(vec![Json::Object(vec![(
+                    "artifactChanges",
+                    Json::Array(changes),
+                )])]),
+            ));
+        }
+        if let Some(warning) = self.warning {
+            let option = Json::str(format!("-W{}", warning.name()));
+            members.push(("properties", Json::Object(vec![("option", option)])));
+        }
+        Json::Object(members)
+    }
+}
+
+/// A point in the source as gcc writes it: the presumed file and line, and the
+/// 1-origin column in characters.
+fn json_point(source_map: &SourceMap, file_id: usize, offset: usize) -> Json {
+    let pos = source_map.position(file_id, offset);
+    let (path, line) = source_map.presumed(&pos);
+    Json::Object(vec![
+        ("file", Json::str(path.display().to_string())),
+        ("line", Json::Num(line)),
+        ("column", Json::Num(pos.n_char + 1)),
+    ])
+}
+
+/// The locations of a diagnostic: none, or the caret at the start of `span` and, if it
+/// is not empty, the finish at its last character.
+fn json_locations(source_map: &SourceMap, span: Option<&Span>) -> Json {
+    let Some(span) = span else {
+        return Json::Array(Vec::new());
+    };
+    let mut location = vec![("caret", json_point(source_map, span.file_id, span.start))];
+    if span.end > span.start {
+        let last = source_map.get(span.file_id).input[..span.end]
+            .char_indices()
+            .last()
+            .map_or(span.start, |(i, _)| i);
+        location.push(("finish", json_point(source_map, span.file_id, last)));
+    }
+    Json::Array(vec![Json::Object(location)])
+}
+
+fn sarif_log(diagnostics: &[Diagnostic], source_map: &SourceMap) -> Json {
+    let driver = Json::Object(vec![
+        ("name", Json::str(env!("CARGO_PKG_NAME"))),
+        ("version", Json::str(env!("CARGO_PKG_VERSION"))),
+    ]);
+    let results = diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.to_sarif(source_map))
+        .collect();
+    let run = Json::Object(vec![
+        ("tool", Json::Object(vec![("driver", driver)])),
+        ("columnKind", Json::str("unicodeCodePoints")),
+        ("results", Json::Array(results)),
+    ]);
+    Json::Object(vec![
+        (
+            "$schema",
+            Json::str("https://json.schemastore.org/sarif-2.1.0.json"),
+        ),
+        ("version", Json::str("2.1.0")),
+        ("runs", Json::Array(vec![run])),
+    ])
+}
+
+fn sarif_message(text: &str) -> Json {
+    Json::Object(vec![("text", Json::str(text))])
+}
+
+fn sarif_physical_location(source_map: &SourceMap, span: &Span) -> Json {
+    Json::Object(vec![
+        (
+            "artifactLocation",
+            sarif_artifact_location(source_map, span),
+        ),
+        ("region", sarif_region(source_map, span)),
+    ])
+}
+
+fn sarif_artifact_location(source_map: &SourceMap, span: &Span) -> Json {
+    let (path, _) = source_map.presumed(&source_map.position(span.file_id, span.start));
+    Json::Object(vec![("uri", Json::str(path.display().to_string()))])
+}
+
+/// The region of `span`, with 1-origin lines and columns and the end column exclusive
+/// as SARIF has them.
+fn sarif_region(source_map: &SourceMap, span: &Span) -> Json {
+    let start = source_map.position(span.file_id, span.start);
+    let end = source_map.position(span.file_id, span.end);
+    Json::Object(vec![
+        ("startLine", Json::Num(source_map.presumed(&start).1)),
+        ("startColumn", Json::Num(start.n_char + 1)),
+        ("endLine", Json::Num(source_map.presumed(&end).1)),
+        ("endColumn", Json::Num(end.n_char + 1)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut source_map = SourceMap::new();
+        source_map.add(Path::new("a.c"), "#define F(x) x\nF(1, 2)\n".to_string());
+
+        let diagnostic = Diagnostic::error(
+            ErrorCode::MacroArguments,
+            Span::new(0, 15, 22),
+            "wrong number of arguments",
+        )
+        .with_label(Span::new(0, 8, 9), "macro defined here")
+        .with_note("F takes 1 argument");
+        assert_eq!(
+            diagnostic.render(&source_map, false),
+            "error[C0020]: wrong number of arguments
+ --> a.c:2:1
+  |
+1 | #define F(x) x
+2 | F(1, 2)
+  | ^~~~~~~
+  = note: F takes 1 argument
+note: macro defined here
+ --> a.c:1:9
+  |
+1 | #define F(x) x
+  |         ^
+2 | F(1, 2)
+"
+        );
+
+        // an empty span gets a caret
+        assert_eq!(
+            Diagnostic::error(ErrorCode::UnexpectedToken, Span::new(0, 23, 23), "y")
+                .render(&source_map, false),
+            "error[C0003]: y\n --> a.c:3:1\n  |\n2 | F(1, 2)\n3 |\n  | ^\n"
+        );
+
+        let warning = Diagnostic::warning(Warning::Cpp, None, "no input");
+        assert_eq!(
+            warning.render(&source_map, false),
+            "warning[C0101]: no input [-Wcpp]\n"
+        );
+        let error = Diagnostic {
+            severity: Severity::Error,
+            ..warning
+        };
+        assert_eq!(
+            error.render(&source_map, false),
+            "error[C0101]: no input [-Werror=cpp]\n"
+        );
+        assert_eq!(
+            error.render(&source_map, true),
+            "\x1b[1;31merror[C0101]\x1b[0m\x1b[1m: no input [-Werror=cpp]\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn test_render_lines() {
+        let mut source_map = SourceMap::new();
+        let input = "\t1 +\n2 +\n3 +\n4 +\n5 +\n6 +\n\n\"\u{65e5}\" 7 +\n";
+        source_map.add(Path::new("a.c"), input.to_string());
+
+        // the lines of a long span are cut in the middle, and empty ones are not context
+        assert_eq!(
+            Diagnostic::error(ErrorCode::UnexpectedToken, Span::new(0, 1, 24), "x")
+                .render(&source_map, false),
+            "error[C0003]: x
+ --> a.c:1:2
+  |
+1 |         1 +
+  |         ^~~
+2 | 2 +
+  | ~~~
+...
+6 | 6 +
+  | ~~~
+"
+        );
+
+        // columns count a wide character as two on screen, and one in the location
+        let start = input.find('7').unwrap();
+        assert_eq!(
+            Diagnostic::error(
+                ErrorCode::UnexpectedToken,
+                Span::new(0, start, start + 1),
+                "y"
+            )
+            .render(&source_map, false),
+            "error[C0003]: y\n --> a.c:8:5\n  |\n8 | \"\u{65e5}\" 7 +\n  |      ^\n"
+        );
+    }
+
+    #[test]
+    fn test_display_width() {
+        assert_eq!(display_width("ab\tc"), 9);
+        assert_eq!(display_width("\u{65e5}\u{672c}"), 4);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(expand_tabs("a\tb\t"), "a       b       ");
+    }
+
+    #[test]
+    fn test_emit_json() {
+        let mut source_map = SourceMap::new();
+        source_map.add(Path::new("a.c"), "#define F(x) x\nF(1, 2)\n".to_string());
+        let diagnostics = [
+            Diagnostic::error(
+                ErrorCode::MacroArguments,
+                Span::new(0, 15, 22),
+                "wrong number of arguments",
+            )
+            .with_label(Span::new(0, 8, 9), "macro defined here")
+            .with_note("F takes 1 argument"),
+            Diagnostic::warning(Warning::Cpp, None, "w"),
+        ];
+        assert_eq!(
+            DiagnosticFormat::Json.emit(&diagnostics, &source_map, false),
+            concat!(
+                r#"[{"kind":"error","code":"C0020","message":"wrong number of arguments","#,
+                r#""locations":[{"caret":{"file":"a.c","line":2,"column":1},"#,
+                r#""finish":{"file":"a.c","line":2,"column":7}}],"#,
+                r#""children":[{"kind":"note","message":"macro defined here","#,
+                r#""locations":[{"caret":{"file":"a.c","line":1,"column":9},"#,
+                r#""finish":{"file":"a.c","line":1,"column":9}}]},"#,
+                r#"{"kind":"note","message":"F takes 1 argument","locations":[]}]},"#,
+                r#"{"kind":"warning","code":"C0101","message":"w","option":"-Wcpp","locations":[],"children":[]}]"#,
+                "\n"
+            )
+        );
+        assert_eq!(DiagnosticFormat::Json.emit(&[], &source_map, false), "[]\n");
+    }
+
+    #[test]
+    fn test_emit_sarif() {
+        let mut source_map = SourceMap::new();
+        source_map.add(Path::new("a.c"), "1 / 0\n".to_string());
+        let diagnostics =
+            [
+                Diagnostic::warning(Warning::DivByZero, Span::new(0, 0, 5), "division by zero")
+                    .with_note("n"),
+            ];
+        let sarif = DiagnosticFormat::Sarif.emit(&diagnostics, &source_map, false);
+        assert!(sarif.starts_with(r#"{"$schema":"#));
+        assert!(sarif.ends_with(concat!(
+            r#""results":[{"ruleId":"C0103","level":"warning","#,
+            r#""message":{"text":"division by zero"},"#,
+            r#""locations":[{"physicalLocation":{"artifactLocation":{"uri":"a.c"},"#,
+            r#""region":{"startLine":1,"startColumn":1,"endLine":1,"endColumn":6}}}],"#,
+            r#""relatedLocations":[{"message":{"text":"n"}}],"#,
+            r#""properties":{"option":"-Wdiv-by-zero"}}]}]}"#,
+            "\n"
+        )));
+    }
+
+    #[test]
+    fn test_fixits() {
+        let mut source_map = SourceMap::new();
+        source_map.add(Path::new("a.c"), "x\n1 || 2 && 3\n".to_string());
+        let diagnostic = Diagnostic::error(ErrorCode::Assignment, Span::new(0, 2, 13), "e")
+            .with_fixit(Span::new(0, 7, 7), "(")
+            .with_fixit(Span::new(0, 13, 13), ")");
+        assert_eq!(
+            diagnostic.render(&source_map, false),
+            "error[C0005]: e
+ --> a.c:2:1
+  |
+1 | x
+2 | 1 || 2 && 3
+  | ^~~~~~~~~~~
+help: insert '(', insert ')'
+  |
+2 | 1 || (2 && 3)
+  |      +      +
+"
+        );
+
+        let fixits = [
+            FixIt {
+                span: Span::new(0, 0, 1),
+                replacement: "y".to_string(),
+            },
+            FixIt {
+                span: Span::new(0, 2, 4),
+                replacement: String::new(),
+            },
+            // overlaps the one before it
+            FixIt {
+                span: Span::new(0, 3, 4),
+                replacement: "z".to_string(),
+            },
+        ];
+        let input = &source_map.get(0).input;
+        assert_eq!(
+            apply_fixits(input, 0, &fixits.iter().collect::<Vec<_>>()),
+            "y\n|| 2 && 3\n"
+        );
+        // out of `text`
+        assert_eq!(apply_fixits(&input[2..], 2, &[&fixits[0]]), &input[2..]);
+    }
+
+    #[test]
+    fn test_closest_name() {
+        let names = ["include", "ifdef", "if", "define"];
+        assert_eq!(closest_name("inlcude", names), Some("include"));
+        assert_eq!(closest_name("ifdfe", names), Some("ifdef"));
+        assert_eq!(closest_name("iff", names), Some("if"));
+        assert_eq!(closest_name("pragma", names), None);
+        assert_eq!(closest_name("if", names), None);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+}
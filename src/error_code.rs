@@ -0,0 +1,165 @@
+use std::fmt;
+
+/// The stable code of a kind of diagnostic, written `C0012`. `--explain` prints the
+/// explanation of a code, which is kept in `explanations/` and embedded in the binary.
+///
+/// Codes are never renumbered or reused: a code that is no longer reported keeps its
+/// number. Errors are numbered from 1 and warnings from 101.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum ErrorCode {
+    UnterminatedComment = 1,
+    /// A stray character, malformed number or unterminated literal
+    InvalidToken = 2,
+    UnexpectedToken = 3,
+    UndeclaredIdentifier = 4,
+    Assignment = 5,
+    DivisionByZero = 6,
+    UnterminatedConditional = 7,
+    /// `#else`, `#elif` or `#endif` out of place
+    UnbalancedConditional = 8,
+    ErrorDirective = 9,
+    InvalidDirective = 10,
+    InvalidLineDirective = 11,
+    InvalidIfExpression = 12,
+    InvalidMacroName = 13,
+    InvalidMacroParameters = 14,
+    /// `#` or `##` misused in a macro definition
+    InvalidMacroBody = 15,
+    IncludeTooDeep = 16,
+    FileNotFound = 17,
+    InvalidIncludeName = 18,
+    /// A file that cannot be read or written
+    Io = 19,
+    MacroArguments = 20,
+    InvalidVaOpt = 21,
+    InvalidPaste = 22,
+    /// The assembler or the linker could not be run or failed
+    ToolFailed = 23,
+    /// An unknown or malformed command-line option
+    InvalidOption = 24,
+    WarningDirective = 101,
+    ExtraTokens = 102,
+    DivByZero = 103,
+    ShiftCountOverflow = 104,
+    ShiftCountNegative = 105,
+    Parentheses = 106,
+    SignCompare = 107,
+    UnusedInput = 108,
+    UnknownWarningOption = 109,
+}
+
+impl ErrorCode {
+    const ALL: [ErrorCode; 33] = [
+        ErrorCode::UnterminatedComment,
+        ErrorCode::InvalidToken,
+        ErrorCode::UnexpectedToken,
+        ErrorCode::UndeclaredIdentifier,
+        ErrorCode::Assignment,
+        ErrorCode::DivisionByZero,
+        ErrorCode::UnterminatedConditional,
+        ErrorCode::UnbalancedConditional,
+        ErrorCode::ErrorDirective,
+        ErrorCode::InvalidDirective,
+        ErrorCode::InvalidLineDirective,
+        ErrorCode::InvalidIfExpression,
+        ErrorCode::InvalidMacroName,
+        ErrorCode::InvalidMacroParameters,
+        ErrorCode::InvalidMacroBody,
+        ErrorCode::IncludeTooDeep,
+        ErrorCode::FileNotFound,
+        ErrorCode::InvalidIncludeName,
+        ErrorCode::Io,
+        ErrorCode::MacroArguments,
+        ErrorCode::InvalidVaOpt,
+        ErrorCode::InvalidPaste,
+        ErrorCode::ToolFailed,
+        ErrorCode::InvalidOption,
+        ErrorCode::WarningDirective,
+        ErrorCode::ExtraTokens,
+        ErrorCode::DivByZero,
+        ErrorCode::ShiftCountOverflow,
+        ErrorCode::ShiftCountNegative,
+        ErrorCode::Parentheses,
+        ErrorCode::SignCompare,
+        ErrorCode::UnusedInput,
+        ErrorCode::UnknownWarningOption,
+    ];
+
+    /// The code written as `C0012`, or `None` if there is no such code.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|code| code.to_string().eq_ignore_ascii_case(name))
+    }
+
+    /// The extended explanation printed by `--explain`, in Markdown with an example.
+    pub fn explanation(self) -> &'static str {
+        match self {
+            ErrorCode::UnterminatedComment => include_str!("../explanations/C0001.md"),
+            ErrorCode::InvalidToken => include_str!("../explanations/C0002.md"),
+            ErrorCode::UnexpectedToken => include_str!("../explanations/C0003.md"),
+            ErrorCode::UndeclaredIdentifier => include_str!("../explanations/C0004.md"),
+            ErrorCode::Assignment => include_str!("../explanations/C0005.md"),
+            ErrorCode::DivisionByZero => include_str!("../explanations/C0006.md"),
+            ErrorCode::UnterminatedConditional => include_str!("../explanations/C0007.md"),
+            ErrorCode::UnbalancedConditional => include_str!("../explanations/C0008.md"),
+            ErrorCode::ErrorDirective => include_str!("../explanations/C0009.md"),
+            ErrorCode::InvalidDirective => include_str!("../explanations/C0010.md"),
+            ErrorCode::InvalidLineDirective => include_str!("../explanations/C0011.md"),
+            ErrorCode::InvalidIfExpression => include_str!("../explanations/C0012.md"),
+            ErrorCode::InvalidMacroName => include_str!("../explanations/C0013.md"),
+            ErrorCode::InvalidMacroParameters => include_str!("../explanations/C0014.md"),
+            ErrorCode::InvalidMacroBody => include_str!("../explanations/C0015.md"),
+            ErrorCode::IncludeTooDeep => include_str!("../explanations/C0016.md"),
+            ErrorCode::FileNotFound => include_str!("../explanations/C0017.md"),
+            ErrorCode::InvalidIncludeName => include_str!("../explanations/C0018.md"),
+            ErrorCode::Io => include_str!("../explanations/C0019.md"),
+            ErrorCode::MacroArguments => include_str!("../explanations/C0020.md"),
+            ErrorCode::InvalidVaOpt => include_str!("../explanations/C0021.md"),
+            ErrorCode::InvalidPaste => include_str!("../explanations/C0022.md"),
+            ErrorCode::ToolFailed => include_str!("../explanations/C0023.md"),
+            ErrorCode::InvalidOption => include_str!("../explanations/C0024.md"),
+            ErrorCode::WarningDirective => include_str!("../explanations/C0101.md"),
+            ErrorCode::ExtraTokens => include_str!("../explanations/C0102.md"),
+            ErrorCode::DivByZero => include_str!("../explanations/C0103.md"),
+            ErrorCode::ShiftCountOverflow => include_str!("../explanations/C0104.md"),
+            ErrorCode::ShiftCountNegative => include_str!("../explanations/C0105.md"),
+            ErrorCode::Parentheses => include_str!("../explanations/C0106.md"),
+            ErrorCode::SignCompare => include_str!("../explanations/C0107.md"),
+            ErrorCode::UnusedInput => include_str!("../explanations/C0108.md"),
+            ErrorCode::UnknownWarningOption => include_str!("../explanations/C0109.md"),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "C{:04}", *self as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(ErrorCode::InvalidIfExpression.to_string(), "C0012");
+        assert_eq!(ErrorCode::from_name("C0107"), Some(ErrorCode::SignCompare));
+        assert_eq!(
+            ErrorCode::from_name("c0003"),
+            Some(ErrorCode::UnexpectedToken)
+        );
+        assert_eq!(ErrorCode::from_name("C0999"), None);
+
+        // every explanation starts with its code, so none is embedded for another
+        for code in ErrorCode::ALL {
+            assert!(
+                code.explanation().starts_with(&format!("# {}: ", code)),
+                "{}",
+                code
+            );
+        }
+    }
+}
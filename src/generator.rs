@@ -2,23 +2,31 @@ use std::io::{BufWriter, Write};
 
 use crate::{
     analyzer::{ConvExpr, ConvExprKind},
+    lexer::IntType,
     parser::BinOpKind,
 };
 
-pub struct Generator {}
+pub struct Generator {
+    /// Number of labels emitted so far, used to make them unique
+    label_count: usize,
+}
 
 #[allow(unused)]
 impl Generator {
     pub fn new() -> Self {
-        Self {}
+        Self { label_count: 0 }
     }
 
-    pub fn gen_head<W: Write>(f: &mut BufWriter<W>, expr: ConvExpr) -> Result<(), std::io::Error> {
+    pub fn gen_head<W: Write>(
+        &mut self,
+        f: &mut BufWriter<W>,
+        expr: ConvExpr,
+    ) -> Result<(), std::io::Error> {
         writeln!(f, ".intel_syntax noprefix")?;
         writeln!(f, ".global main")?;
         writeln!(f, "main:")?;
 
-        Self::gen_expr(f, expr)?;
+        self.gen_expr(f, expr)?;
         writeln!(f, "  pop rax")?;
         writeln!(f, "  ret")?;
 
@@ -27,20 +35,45 @@ impl Generator {
         Ok(())
     }
 
-    pub fn gen_expr<W: Write>(f: &mut BufWriter<W>, expr: ConvExpr) -> Result<(), std::io::Error> {
+    pub fn gen_expr<W: Write>(
+        &mut self,
+        f: &mut BufWriter<W>,
+        expr: ConvExpr,
+    ) -> Result<(), std::io::Error> {
+        let ty = expr.ty;
         match expr.kind {
             ConvExprKind::Num(num) => {
-                writeln!(f, "  push {}", num)?;
+                if i32::try_from(num).is_ok() {
+                    writeln!(f, "  push {}", num)?;
+                } else {
+                    // `push` only takes a sign-extended 32-bit immediate
+                    writeln!(f, "  mov rax, {}", num)?;
+                    writeln!(f, "  push rax")?;
+                }
             }
             ConvExprKind::Binary(binary) => {
-                Self::gen_expr(f, *binary.lhs)?;
-                Self::gen_expr(f, *binary.rhs)?;
+                let operand_ty = binary.operand_type();
+                let unsigned = operand_ty.is_unsigned();
+                self.gen_expr(f, *binary.lhs)?;
+                self.gen_expr(f, *binary.rhs)?;
                 writeln!(f, "  pop rdi")?;
                 writeln!(f, "  pop rax")?;
+                Self::gen_convert(f, operand_ty, "rax", "eax")?;
+                if !matches!(binary.kind, BinOpKind::Shl | BinOpKind::Shr) {
+                    Self::gen_convert(f, operand_ty, "rdi", "edi")?;
+                }
                 match binary.kind {
                     BinOpKind::Add => writeln!(f, "  add rax, rdi")?,
                     BinOpKind::Sub => writeln!(f, "  sub rax, rdi")?,
                     BinOpKind::Mul => writeln!(f, "  imul rax, rdi")?,
+                    BinOpKind::Div | BinOpKind::Rem if unsigned => {
+                        // rdx-rax = rax, zero-extended
+                        writeln!(f, "  xor edx, edx")?;
+                        writeln!(f, "  div rdi")?;
+                        if binary.kind == BinOpKind::Rem {
+                            writeln!(f, "  mov rax, rdx")?;
+                        }
+                    }
                     BinOpKind::Div => {
                         // rdx-rax = rax
                         writeln!(f, "  cqo")?;
@@ -48,10 +81,89 @@ impl Generator {
                         // rdx = rdx-rax % rdi
                         writeln!(f, "  idiv rdi")?;
                     }
+                    BinOpKind::Rem => {
+                        writeln!(f, "  cqo")?;
+                        writeln!(f, "  idiv rdi")?;
+                        writeln!(f, "  mov rax, rdx")?;
+                    }
+                    BinOpKind::BitXor => writeln!(f, "  xor rax, rdi")?,
+                    BinOpKind::BitAnd => writeln!(f, "  and rax, rdi")?,
+                    BinOpKind::BitOr => writeln!(f, "  or rax, rdi")?,
+                    BinOpKind::Shl => {
+                        writeln!(f, "  mov rcx, rdi")?;
+                        writeln!(f, "  shl rax, cl")?;
+                    }
+                    BinOpKind::Shr => {
+                        writeln!(f, "  mov rcx, rdi")?;
+                        writeln!(f, "  {} rax, cl", if unsigned { "shr" } else { "sar" })?;
+                    }
+                    BinOpKind::Eq
+                    | BinOpKind::Ne
+                    | BinOpKind::Lt
+                    | BinOpKind::Le
+                    | BinOpKind::Gt
+                    | BinOpKind::Ge => {
+                        let set = match (&binary.kind, unsigned) {
+                            (BinOpKind::Eq, _) => "sete",
+                            (BinOpKind::Ne, _) => "setne",
+                            (BinOpKind::Lt, false) => "setl",
+                            (BinOpKind::Le, false) => "setle",
+                            (BinOpKind::Gt, false) => "setg",
+                            (_, false) => "setge",
+                            (BinOpKind::Lt, true) => "setb",
+                            (BinOpKind::Le, true) => "setbe",
+                            (BinOpKind::Gt, true) => "seta",
+                            (_, true) => "setae",
+                        };
+                        writeln!(f, "  cmp rax, rdi")?;
+                        writeln!(f, "  {} al", set)?;
+                        writeln!(f, "  movzx rax, al")?;
+                    }
+                    BinOpKind::And | BinOpKind::Or => {
+                        unreachable!("`&&` and `||` are lowered by the analyzer")
+                    }
                 }
+                Self::gen_convert(f, ty, "rax", "eax")?;
                 writeln!(f, "  push rax")?;
             }
+            ConvExprKind::Cond(cond, then, els) => {
+                let label = self.label_count;
+                self.label_count += 1;
+
+                self.gen_expr(f, *cond)?;
+                writeln!(f, "  pop rax")?;
+                writeln!(f, "  cmp rax, 0")?;
+                writeln!(f, "  je .Lelse{}", label)?;
+                self.gen_expr(f, *then)?;
+                writeln!(f, "  jmp .Lend{}", label)?;
+                writeln!(f, ".Lelse{}:", label)?;
+                self.gen_expr(f, *els)?;
+                writeln!(f, ".Lend{}:", label)?;
+                // an int branch of an unsigned int conditional loses its sign
+                if ty == IntType::UInt {
+                    writeln!(f, "  pop rax")?;
+                    Self::gen_convert(f, ty, "rax", "eax")?;
+                    writeln!(f, "  push rax")?;
+                }
+            }
         }
         Ok(())
     }
+
+    /// Convert the value in the 64-bit register `reg`, whose lower half is `reg32`, to
+    /// `ty`. Values are kept sign- or zero-extended to 64 bits, so only the 32-bit
+    /// types need anything done.
+    fn gen_convert<W: Write>(
+        f: &mut BufWriter<W>,
+        ty: IntType,
+        reg: &str,
+        reg32: &str,
+    ) -> Result<(), std::io::Error> {
+        match ty {
+            IntType::Int => writeln!(f, "  movsxd {}, {}", reg, reg32),
+            // writing a 32-bit register clears the upper half
+            IntType::UInt => writeln!(f, "  mov {}, {}", reg32, reg32),
+            _ => Ok(()),
+        }
+    }
 }
@@ -0,0 +1,84 @@
+use std::fmt;
+
+/// A JSON value, enough to write the machine-readable diagnostics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Json {
+    Num(usize),
+    Str(String),
+    Array(Vec<Json>),
+    /// Members in the order they are written
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    pub fn str(s: impl Into<String>) -> Self {
+        Json::Str(s.into())
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Num(n) => write!(f, "{}", n),
+            Json::Str(s) => write_str(f, s),
+            Json::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let json = Json::Object(vec![
+            ("n", Json::Num(1)),
+            ("s", Json::str("a \"b\"\\\n\u{1}é")),
+            (
+                "a",
+                Json::Array(vec![Json::Num(2), Json::Array(Vec::new())]),
+            ),
+            ("o", Json::Object(Vec::new())),
+        ]);
+        assert_eq!(
+            json.to_string(),
+            r#"{"n":1,"s":"a \"b\"\\\n\u0001é","a":[2,[]],"o":{}}"#
+        );
+    }
+}
@@ -1,27 +1,51 @@
+use std::fmt;
 use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::diagnostic::Diagnostic;
+use crate::error_code::ErrorCode;
 
 pub struct Lexer<'a> {
     pub input: &'a str,
+    /// The `SourceMap` id of the file being lexed, recorded in every `Position`
+    pub file_id: usize,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
-        Self { input }
+        Self::with_file_id(input, 0)
+    }
+
+    pub fn with_file_id(input: &'a str, file_id: usize) -> Self {
+        Self { input, file_id }
     }
 
-    pub fn tokenize(&self) -> Vec<Token> {
+    pub fn tokenize(&self) -> Result<Vec<Token>, Diagnostic> {
         let mut tokens = Vec::new();
         let mut input_chars = self.input.chars().peekable();
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(self.input.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
 
-        let mut pos = Position::default();
+        let mut pos = Position {
+            file_id: self.file_id,
+            ..Default::default()
+        };
+        let mut at_bol = true;
+        let mut has_space = false;
 
         while let Some(c) = input_chars.next() {
+            let n_tokens = tokens.len();
+
             match c {
                 ' ' | '\t' => {
                     pos.next_char();
+                    has_space = true;
                 }
                 '\n' => {
                     pos.next_line();
+                    at_bol = true;
+                    has_space = false;
                 }
                 '+' => tokens.push(Token::new(
                     TokenKind::BinOp(BinOpToken::Plus),
@@ -36,10 +60,65 @@ impl<'a> Lexer<'a> {
                     pos.next_char(),
                 )),
                 '/' => {
-                    tokens.push(Token::new(
-                        TokenKind::BinOp(BinOpToken::Div),
-                        pos.next_char(),
-                    ));
+                    let start = pos.next_char();
+                    Self::skip_line_splices(&mut input_chars, &mut pos);
+                    match input_chars.peek() {
+                        // line comment: runs up to (but not including) the newline
+                        Some('/') => {
+                            has_space = true;
+                            input_chars.next();
+                            pos.next_char();
+                            loop {
+                                Self::skip_line_splices(&mut input_chars, &mut pos);
+                                match input_chars.peek() {
+                                    Some('\n') | None => break,
+                                    Some(_) => {
+                                        input_chars.next();
+                                        pos.next_char();
+                                    }
+                                }
+                            }
+                        }
+                        // block comment
+                        Some('*') => {
+                            has_space = true;
+                            input_chars.next();
+                            pos.next_char();
+                            loop {
+                                match input_chars.next() {
+                                    Some('*') => {
+                                        pos.next_char();
+                                        Self::skip_line_splices(&mut input_chars, &mut pos);
+                                        if input_chars.peek() == Some(&'/') {
+                                            input_chars.next();
+                                            pos.next_char();
+                                            break;
+                                        }
+                                    }
+                                    Some('\n') => {
+                                        pos.next_line();
+                                    }
+                                    Some(_) => {
+                                        pos.next_char();
+                                    }
+                                    None => {
+                                        let start = self.offset(&line_starts, &start);
+                                        return Err(Diagnostic::error(
+                                            ErrorCode::UnterminatedComment,
+                                            Span::new(self.file_id, start, start + 2),
+                                            "unterminated comment",
+                                        ));
+                                    }
+                                }
+                            }
+                        }
+                        _ => tokens.push(Token::new(TokenKind::BinOp(BinOpToken::Div), start)),
+                    }
+                }
+                // backslash-newline is deleted (line splicing)
+                '\\' if input_chars.peek() == Some(&'\n') => {
+                    input_chars.next();
+                    pos.next_line();
                 }
                 '(' => tokens.push(Token::new(
                     TokenKind::OpenDelim(DelimToken::Paren),
@@ -49,6 +128,104 @@ impl<'a> Lexer<'a> {
                     TokenKind::CloseDelim(DelimToken::Paren),
                     pos.next_char(),
                 )),
+                '%' | '^' | '~' | '?' | ':' | '!' | '=' | '<' | '>' | '&' | '|' => {
+                    let start = pos.next_char();
+                    Self::skip_line_splices(&mut input_chars, &mut pos);
+                    let (kind, len_token) = match (c, input_chars.peek()) {
+                        ('=', Some('=')) => (TokenKind::EqEq, 2),
+                        ('!', Some('=')) => (TokenKind::Ne, 2),
+                        ('<', Some('=')) => (TokenKind::Le, 2),
+                        ('>', Some('=')) => (TokenKind::Ge, 2),
+                        ('<', Some('<')) => (TokenKind::BinOp(BinOpToken::Shl), 2),
+                        ('>', Some('>')) => (TokenKind::BinOp(BinOpToken::Shr), 2),
+                        ('&', Some('&')) => (TokenKind::AndAnd, 2),
+                        ('|', Some('|')) => (TokenKind::OrOr, 2),
+                        ('%', _) => (TokenKind::BinOp(BinOpToken::Percent), 1),
+                        ('^', _) => (TokenKind::BinOp(BinOpToken::Caret), 1),
+                        ('&', _) => (TokenKind::BinOp(BinOpToken::And), 1),
+                        ('|', _) => (TokenKind::BinOp(BinOpToken::Or), 1),
+                        ('~', _) => (TokenKind::Tilde, 1),
+                        ('?', _) => (TokenKind::Question, 1),
+                        (':', _) => (TokenKind::Colon, 1),
+                        ('!', _) => (TokenKind::Not, 1),
+                        ('<', _) => (TokenKind::Lt, 1),
+                        ('>', _) => (TokenKind::Gt, 1),
+                        // assignment is not supported yet
+                        _ => (TokenKind::Unknown(c.to_string()), 1),
+                    };
+                    if len_token == 2 {
+                        input_chars.next();
+                        pos.next_char();
+                    }
+                    tokens.push(Token::new(kind, start));
+                }
+                ',' => tokens.push(Token::new(TokenKind::Comma, pos.next_char())),
+                '#' => {
+                    let start = pos.next_char();
+                    Self::skip_line_splices(&mut input_chars, &mut pos);
+                    if input_chars.next_if_eq(&'#').is_some() {
+                        pos.next_char();
+                        tokens.push(Token::new(TokenKind::PoundPound, start));
+                    } else {
+                        tokens.push(Token::new(TokenKind::Pound, start));
+                    }
+                }
+                '.' => {
+                    let mut lookahead = input_chars.clone();
+                    if lookahead.next() == Some('.') && lookahead.next() == Some('.') {
+                        input_chars.next();
+                        input_chars.next();
+                        tokens.push(Token::new(TokenKind::DotDotDot, pos.next_token(3)));
+                    } else {
+                        tokens.push(Token::new(
+                            TokenKind::Unknown(c.to_string()),
+                            pos.next_char(),
+                        ));
+                    }
+                }
+                '"' => {
+                    let start = pos.next_char();
+                    // The contents are kept as written; escape sequences are not interpreted.
+                    let (content, terminated) = Self::read_quoted(&mut input_chars, &mut pos, '"');
+                    let kind = if terminated {
+                        TokenKind::Str(content)
+                    } else {
+                        TokenKind::Unknown(format!("\"{}", content))
+                    };
+                    tokens.push(Token::new(kind, start));
+                }
+                '\'' => {
+                    let start = pos.next_char();
+                    let (kind, spelling) = Self::char_constant(&mut input_chars, &mut pos, "");
+                    let mut token = Token::new(kind, start);
+                    token.spelling = Some(spelling);
+                    tokens.push(token);
+                }
+                'a'..='z' | 'A'..='Z' | '_' => {
+                    let start = pos.next_char();
+                    let mut ident = c.to_string();
+                    loop {
+                        Self::skip_line_splices(&mut input_chars, &mut pos);
+                        match input_chars.next_if(|&c| c.is_ascii_alphanumeric() || c == '_') {
+                            Some(next_char) => {
+                                ident.push(next_char);
+                                pos.next_char();
+                            }
+                            None => break,
+                        }
+                    }
+                    let is_prefix = matches!(ident.as_str(), "L" | "u" | "U");
+                    if is_prefix && input_chars.next_if_eq(&'\'').is_some() {
+                        pos.next_char();
+                        let (kind, spelling) =
+                            Self::char_constant(&mut input_chars, &mut pos, &ident);
+                        let mut token = Token::new(kind, start);
+                        token.spelling = Some(spelling);
+                        tokens.push(token);
+                    } else {
+                        tokens.push(Token::new(TokenKind::Ident(ident), start));
+                    }
+                }
                 // '{' => tokens.push(Token::new(
                 //     TokenKind::OpenDelim(DelimToken::Brace),
                 //     pos.next_char(),
@@ -66,50 +243,365 @@ impl<'a> Lexer<'a> {
                 //     pos.next_char(),
                 // )),
                 '0'..='9' => {
+                    let start = pos.next_char();
+                    // Take the whole pp-number so that prefixes (`0x`) and suffixes (`ul`)
+                    // are validated together with the digits.
                     let mut number = c.to_string();
-                    while let Some(&next_char) = input_chars.peek() {
-                        if next_char.is_ascii_digit() {
-                            number.push(next_char);
-                            input_chars.next();
-                        } else {
-                            break;
+                    loop {
+                        Self::skip_line_splices(&mut input_chars, &mut pos);
+                        match input_chars.peek() {
+                            Some(&next_char)
+                                if next_char.is_ascii_alphanumeric() || next_char == '_' =>
+                            {
+                                number.push(next_char);
+                                input_chars.next();
+                                pos.next_char();
+                            }
+                            _ => break,
+                        }
+                    }
+
+                    // A malformed number is only an error if it survives preprocessing.
+                    let kind = match parse_int_literal(&number) {
+                        Ok((num, ty)) => TokenKind::Num(num, ty),
+                        Err(_) => TokenKind::Unknown(number.clone()),
+                    };
+                    let mut token = Token::new(kind, start);
+                    token.spelling = Some(number);
+                    tokens.push(token);
+                }
+                _ => tokens.push(Token::new(
+                    TokenKind::Unknown(c.to_string()),
+                    pos.next_char(),
+                )),
+            }
+
+            if let Some(token) = tokens.get_mut(n_tokens) {
+                token.span = Span::new(
+                    self.file_id,
+                    self.offset(&line_starts, &token.pos),
+                    self.offset(&line_starts, &pos),
+                );
+                token.at_bol = at_bol;
+                token.has_space = has_space;
+                at_bol = false;
+                has_space = false;
+            }
+        }
+
+        let mut eof = Token::new(TokenKind::Eof, pos.next_token(0));
+        let end = self.offset(&line_starts, &pos);
+        eof.span = Span::new(self.file_id, end, end);
+        eof.at_bol = at_bol;
+        eof.has_space = has_space;
+        tokens.push(eof);
+
+        Ok(tokens)
+    }
+
+    /// The byte offset in the input of `pos`, whose line starts at `line_starts[pos.n_line]`.
+    fn offset(&self, line_starts: &[usize], pos: &Position) -> usize {
+        let line_start = line_starts[pos.n_line];
+        self.input[line_start..]
+            .char_indices()
+            .nth(pos.n_char)
+            .map_or(self.input.len(), |(i, _)| line_start + i)
+    }
+
+    /// Read the rest of a string literal or character constant after its opening
+    /// `quote`, up to the closing one. Returns the contents as written and whether the
+    /// closing quote was found before the end of the line.
+    fn read_quoted(chars: &mut Peekable<Chars>, pos: &mut Position, quote: char) -> (String, bool) {
+        let mut content = String::new();
+        loop {
+            Self::skip_line_splices(chars, pos);
+            match chars.peek() {
+                Some(&c) if c == quote => {
+                    chars.next();
+                    pos.next_char();
+                    return (content, true);
+                }
+                Some('\n') | None => return (content, false),
+                Some(&next_char) => {
+                    content.push(next_char);
+                    chars.next();
+                    pos.next_char();
+                    if next_char == '\\' {
+                        Self::skip_line_splices(chars, pos);
+                        if let Some(escaped) = chars.next_if(|&escaped| escaped != '\n') {
+                            content.push(escaped);
+                            pos.next_char();
                         }
                     }
+                }
+            }
+        }
+    }
+
+    /// Lex a character constant after its `prefix` and opening `'` into its value.
+    /// Also returns its spelling.
+    fn char_constant(
+        chars: &mut Peekable<Chars>,
+        pos: &mut Position,
+        prefix: &str,
+    ) -> (TokenKind, String) {
+        let (content, terminated) = Self::read_quoted(chars, pos, '\'');
+        let spelling = format!(
+            "{}'{}{}",
+            prefix,
+            content,
+            if terminated { "'" } else { "" }
+        );
+        let kind = match terminated.then(|| parse_char_constant(prefix, &content)) {
+            Some(Ok((value, ty))) => TokenKind::Num(value, ty),
+            _ => TokenKind::Unknown(spelling.clone()),
+        };
+        (kind, spelling)
+    }
+
+    /// Skip any backslash-newline pairs at the head of `chars`.
+    fn skip_line_splices(chars: &mut Peekable<Chars>, pos: &mut Position) {
+        loop {
+            let mut lookahead = chars.clone();
+            if lookahead.next() == Some('\\') && lookahead.next() == Some('\n') {
+                chars.next();
+                chars.next();
+                pos.next_line();
+            } else {
+                break;
+            }
+        }
+    }
+}
 
-                    let len_token = number.len();
-                    let num = number.parse::<isize>().unwrap();
-                    tokens.push(Token::new(TokenKind::Num(num), pos.next_token(len_token)));
+/// Parse an integer constant (C11 6.4.4.1) and pick its type from the suffix and the value.
+///
+/// The value is returned as the 64-bit pattern of the constant, so `0xffffffffffffffff`
+/// comes back as `-1` typed `unsigned long`.
+fn parse_int_literal(spelling: &str) -> Result<(isize, IntType), String> {
+    let lower = spelling.to_ascii_lowercase();
+    let (radix, body) = if let Some(rest) = lower.strip_prefix("0x") {
+        (16, rest)
+    } else if let Some(rest) = lower.strip_prefix("0b") {
+        (2, rest)
+    } else if lower.starts_with('0') {
+        (8, &lower[..])
+    } else {
+        (10, &lower[..])
+    };
+
+    // Octal and binary digits are scanned as decimal so that `09` reports a bad digit
+    // rather than a bad suffix.
+    let scan_radix = if radix == 16 { 16 } else { 10 };
+    let n_digits = body
+        .find(|c: char| !c.is_digit(scan_radix))
+        .unwrap_or(body.len());
+    let (digits, suffix) = body.split_at(n_digits);
+    // The suffix is matched case-insensitively except for `lL` / `Ll`, which C forbids.
+    let raw_suffix = &spelling[spelling.len() - suffix.len()..];
+    if raw_suffix.contains("lL") || raw_suffix.contains("Ll") {
+        return Err(format!("invalid suffix {:?}", raw_suffix));
+    }
+    let (unsigned, n_long) = match suffix {
+        "" => (false, 0),
+        "u" => (true, 0),
+        "l" => (false, 1),
+        "ul" | "lu" => (true, 1),
+        "ll" => (false, 2),
+        "ull" | "llu" => (true, 2),
+        _ => return Err(format!("invalid suffix {:?}", raw_suffix)),
+    };
+    if digits.is_empty() {
+        return Err("no digits".to_string());
+    }
+    if let Some(c) = digits.chars().find(|c| !c.is_digit(radix)) {
+        return Err(format!("invalid digit {:?}", c));
+    }
+
+    let value = u64::from_str_radix(digits, radix)
+        .map_err(|_| "value too large for any integer type".to_string())?;
+
+    // Candidate types in order, per the table in C11 6.4.4.1p5.
+    use IntType::*;
+    let candidates: &[IntType] = match (radix == 10, unsigned, n_long) {
+        (true, false, 0) => &[Int, Long, LongLong],
+        (false, false, 0) => &[Int, UInt, Long, ULong, LongLong, ULongLong],
+        (_, true, 0) => &[UInt, ULong, ULongLong],
+        (true, false, 1) => &[Long, LongLong],
+        (false, false, 1) => &[Long, ULong, LongLong, ULongLong],
+        (_, true, 1) => &[ULong, ULongLong],
+        (true, false, _) => &[LongLong],
+        (false, false, _) => &[LongLong, ULongLong],
+        (_, true, _) => &[ULongLong],
+    };
+    let ty = candidates
+        .iter()
+        .find(|ty| value <= ty.max_value())
+        .ok_or_else(|| "value too large for its type".to_string())?;
+
+    Ok((value as isize, *ty))
+}
+
+/// Evaluate a character constant (C11 6.4.4.4) from its prefix and the contents between
+/// the quotes.
+fn parse_char_constant(prefix: &str, content: &str) -> Result<(isize, IntType), String> {
+    // the largest value of the character type, which escapes may not exceed
+    let max_escape = match prefix {
+        "" => 0xff,
+        "u" => 0xffff,
+        _ => u32::MAX,
+    };
+    let mut chars = content.chars().peekable();
+    let mut values = Vec::new();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            values.push(c as u32);
+            continue;
+        }
+        let escaped = chars.next().ok_or("incomplete escape sequence")?;
+        let value = match escaped {
+            'n' => '\n' as u32,
+            't' => '\t' as u32,
+            'r' => '\r' as u32,
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'v' => 0x0b,
+            '\\' | '\'' | '"' | '?' => escaped as u32,
+            '0'..='7' => {
+                let mut value = escaped.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.next_if(|c| c.is_digit(8)) {
+                        Some(c) => value = value * 8 + c.to_digit(8).unwrap(),
+                        None => break,
+                    }
+                }
+                if value > max_escape {
+                    return Err("octal escape sequence out of range".to_string());
                 }
-                _ => self.error_at(
-                    &pos,
-                    &format!("Unexpected character while tokenize: {:?}", &pos),
-                ),
+                value
             }
+            'x' => {
+                let mut digits = String::new();
+                while let Some(c) = chars.next_if(char::is_ascii_hexdigit) {
+                    digits.push(c);
+                }
+                if digits.is_empty() {
+                    return Err("\\x used with no following hex digits".to_string());
+                }
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .filter(|value| *value <= max_escape)
+                    .ok_or("hex escape sequence out of range")?
+            }
+            _ => return Err(format!("unknown escape sequence '\\{}'", escaped)),
+        };
+        values.push(value);
+    }
+
+    match (prefix, values.as_slice()) {
+        (_, []) => Err("no characters".to_string()),
+        // `char` is signed on x86-64
+        ("", [value]) if *value <= 0xff => Ok((*value as u8 as i8 as isize, IntType::Int)),
+        // a multi-character constant packs its bytes into an `int`, as gcc does
+        ("", values) => Ok((
+            values
+                .iter()
+                .fold(0i32, |acc, value| (acc << 8) | (*value as u8 as i32)) as isize,
+            IntType::Int,
+        )),
+        // `wchar_t` is `int`, and `char16_t` (`unsigned short`) is promoted to `int`
+        ("L", [value]) => Ok((*value as i32 as isize, IntType::Int)),
+        ("u", [value]) if *value <= 0xffff => Ok((*value as isize, IntType::Int)),
+        ("U", [value]) => Ok((*value as isize, IntType::UInt)),
+        _ => Err("character too large for its type".to_string()),
+    }
+}
+
+/// The type of an integer constant on x86-64 (LP64).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntType {
+    Int,
+    UInt,
+    Long,
+    ULong,
+    LongLong,
+    ULongLong,
+}
+
+impl IntType {
+    pub fn max_value(&self) -> u64 {
+        match self {
+            IntType::Int => i32::MAX as u64,
+            IntType::UInt => u32::MAX as u64,
+            IntType::Long | IntType::LongLong => i64::MAX as u64,
+            IntType::ULong | IntType::ULongLong => u64::MAX,
         }
+    }
+
+    pub fn is_unsigned(&self) -> bool {
+        matches!(self, IntType::UInt | IntType::ULong | IntType::ULongLong)
+    }
 
-        tokens.push(Token::new(TokenKind::Eof, pos.next_token(0)));
+    /// The width in bits
+    pub fn width(&self) -> usize {
+        match self {
+            IntType::Int | IntType::UInt => 32,
+            _ => 64,
+        }
+    }
 
-        tokens
+    fn rank(&self) -> usize {
+        match self {
+            IntType::Int | IntType::UInt => 0,
+            IntType::Long | IntType::ULong => 1,
+            IntType::LongLong | IntType::ULongLong => 2,
+        }
     }
 
-    pub fn error_at(&self, pos: &Position, msg: &str) -> ! {
-        let mut splitted = self.input.split('\n');
-        let line = splitted.nth(pos.n_line).unwrap_or_else(|| {
-            panic!(
-                "Position is illeagl, pos: {:?}, \n input: {}",
-                pos, self.input
-            );
-        });
+    fn unsigned_counterpart(&self) -> IntType {
+        match self {
+            IntType::Int | IntType::UInt => IntType::UInt,
+            IntType::Long | IntType::ULong => IntType::ULong,
+            IntType::LongLong | IntType::ULongLong => IntType::ULongLong,
+        }
+    }
 
-        eprintln!("{}", line);
-        let mut buffer = String::with_capacity(pos.n_char + 1);
-        for _ in 0..pos.n_char {
-            buffer.push(' ');
+    /// The type both operands of a binary operator are converted to, by the usual
+    /// arithmetic conversions (C11 6.3.1.8).
+    pub fn common(&self, other: &IntType) -> IntType {
+        let (unsigned, signed) = match (self.is_unsigned(), other.is_unsigned()) {
+            (true, false) => (self, other),
+            (false, true) => (other, self),
+            _ => {
+                return if self.rank() >= other.rank() {
+                    *self
+                } else {
+                    *other
+                };
+            }
+        };
+        if unsigned.rank() >= signed.rank() {
+            *unsigned
+        } else if signed.width() > unsigned.width() {
+            *signed
+        } else {
+            signed.unsigned_counterpart()
         }
-        buffer.push('^');
-        eprintln!("{}", buffer);
-        eprintln!("Error: {}", msg);
-        panic!()
+    }
+}
+
+impl fmt::Display for IntType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            IntType::Int => "int",
+            IntType::UInt => "unsigned int",
+            IntType::Long => "long",
+            IntType::ULong => "unsigned long",
+            IntType::LongLong => "long long",
+            IntType::ULongLong => "unsigned long long",
+        };
+        write!(f, "{}", name)
     }
 }
 
@@ -119,19 +611,156 @@ pub enum BinOpToken {
     Minus,
     Mul,
     Div,
+    Percent,
+    Caret,
+    And,
+    Or,
+    Shl,
+    Shr,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TokenKind {
     BinOp(BinOpToken),
-    Num(isize),
+    Num(isize, IntType),
     /// An opening delimiter e.g., `{`
     OpenDelim(DelimToken),
     /// An closing delimiter e.g., `}`
     CloseDelim(DelimToken),
+    Ident(String),
+    /// A string literal, without the quotes and with escapes left as written
+    Str(String),
+    EqEq,
+    Lt,
+    Le,
+    Ne,
+    Ge,
+    Gt,
+    AndAnd,
+    OrOr,
+    Not,
+    Tilde,
+    Question,
+    Colon,
+    Comma,
+    /// `#`
+    Pound,
+    /// `##`
+    PoundPound,
+    /// `...`
+    DotDotDot,
+    /// Anything that is not a valid token by itself (a stray character, a malformed
+    /// number, an unterminated string). Kept as written so that it can still be
+    /// preprocessed; it is an error only if it reaches the parser.
+    Unknown(String),
+    /// A `#pragma` directive the preprocessor passes on, with the tokens after `pragma`.
+    /// `TokenStream` hands it to its pragma hook instead of yielding it.
+    Pragma(Vec<Token>),
     Eof,
 }
 
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::BinOp(BinOpToken::Plus) => write!(f, "+"),
+            TokenKind::BinOp(BinOpToken::Minus) => write!(f, "-"),
+            TokenKind::BinOp(BinOpToken::Mul) => write!(f, "*"),
+            TokenKind::BinOp(BinOpToken::Div) => write!(f, "/"),
+            TokenKind::BinOp(BinOpToken::Percent) => write!(f, "%"),
+            TokenKind::BinOp(BinOpToken::Caret) => write!(f, "^"),
+            TokenKind::BinOp(BinOpToken::And) => write!(f, "&"),
+            TokenKind::BinOp(BinOpToken::Or) => write!(f, "|"),
+            TokenKind::BinOp(BinOpToken::Shl) => write!(f, "<<"),
+            TokenKind::BinOp(BinOpToken::Shr) => write!(f, ">>"),
+            TokenKind::Num(num, ty) => {
+                let suffix = match ty {
+                    IntType::Int => "",
+                    IntType::UInt => "u",
+                    IntType::Long => "l",
+                    IntType::ULong => "ul",
+                    IntType::LongLong => "ll",
+                    IntType::ULongLong => "ull",
+                };
+                if matches!(ty, IntType::Int | IntType::Long | IntType::LongLong) {
+                    write!(f, "{}{}", num, suffix)
+                } else {
+                    write!(f, "{}{}", *num as u64, suffix)
+                }
+            }
+            TokenKind::OpenDelim(DelimToken::Paren) => write!(f, "("),
+            TokenKind::OpenDelim(DelimToken::Brace) => write!(f, "{{"),
+            TokenKind::OpenDelim(DelimToken::Bracket) => write!(f, "["),
+            TokenKind::CloseDelim(DelimToken::Paren) => write!(f, ")"),
+            TokenKind::CloseDelim(DelimToken::Brace) => write!(f, "}}"),
+            TokenKind::CloseDelim(DelimToken::Bracket) => write!(f, "]"),
+            TokenKind::Ident(ident) => write!(f, "{}", ident),
+            TokenKind::Str(content) => write!(f, "\"{}\"", content),
+            TokenKind::EqEq => write!(f, "=="),
+            TokenKind::Lt => write!(f, "<"),
+            TokenKind::Le => write!(f, "<="),
+            TokenKind::Ne => write!(f, "!="),
+            TokenKind::Ge => write!(f, ">="),
+            TokenKind::Gt => write!(f, ">"),
+            TokenKind::AndAnd => write!(f, "&&"),
+            TokenKind::OrOr => write!(f, "||"),
+            TokenKind::Not => write!(f, "!"),
+            TokenKind::Tilde => write!(f, "~"),
+            TokenKind::Question => write!(f, "?"),
+            TokenKind::Colon => write!(f, ":"),
+            TokenKind::Comma => write!(f, ","),
+            TokenKind::Pound => write!(f, "#"),
+            TokenKind::PoundPound => write!(f, "##"),
+            TokenKind::DotDotDot => write!(f, "..."),
+            TokenKind::Unknown(spelling) => write!(f, "{}", spelling),
+            TokenKind::Pragma(tokens) => {
+                write!(f, "#pragma")?;
+                for (i, token) in tokens.iter().enumerate() {
+                    if i == 0 || token.has_space {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", token.kind)?;
+                }
+                Ok(())
+            }
+            TokenKind::Eof => Ok(()),
+        }
+    }
+}
+
+/// Describe why the spelling of a `TokenKind::Unknown` is not a valid token.
+pub fn describe_unknown(spelling: &str) -> String {
+    let char_constant = spelling
+        .trim_start_matches(['L', 'u', 'U'])
+        .strip_prefix('\'');
+    if spelling.starts_with('"') {
+        "missing terminating '\"' character".to_string()
+    } else if let Some(content) = char_constant {
+        // the last `'` closes the constant unless it is escaped
+        let closed = content
+            .strip_suffix('\'')
+            .filter(|inner| inner.chars().rev().take_while(|&c| c == '\\').count() % 2 == 0);
+        match closed {
+            Some(inner) => {
+                let prefix = &spelling[..spelling.len() - content.len() - 1];
+                match parse_char_constant(prefix, inner) {
+                    Err(msg) => format!("{} in character constant {}", msg, spelling),
+                    Ok(_) => {
+                        unreachable!("valid character constant lexed as unknown: {}", spelling)
+                    }
+                }
+            }
+            None => "missing terminating ' character".to_string(),
+        }
+    } else if spelling.starts_with(|c: char| c.is_ascii_digit()) {
+        match parse_int_literal(spelling) {
+            Err(msg) => format!("{} in integer constant {:?}", msg, spelling),
+            Ok(_) => unreachable!("valid integer constant lexed as unknown: {}", spelling),
+        }
+    } else {
+        format!("stray {:?} in program", spelling)
+    }
+}
+
 #[allow(unused)]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DelimToken {
@@ -144,22 +773,47 @@ pub enum DelimToken {
 pub struct Token {
     pub kind: Box<TokenKind>,
     pub pos: Position,
+    /// The source text of the token. A token that comes out of a macro expansion has the
+    /// span of the invocation.
+    pub span: Span,
+    /// Whether this is the first token on its (spliced) line
+    pub at_bol: bool,
+    /// Whether whitespace or a comment precedes this token
+    pub has_space: bool,
+    /// How a number or character constant was written, which its kind does not keep
+    pub spelling: Option<String>,
 }
 
 pub struct TokenStream<'a, I: Iterator<Item = Token>> {
     iter: Peekable<I>,
-    input: &'a str,
+    /// Called with each `TokenKind::Pragma` as it is reached
+    pragma_hook: Option<PragmaHook<'a>>,
 }
 
+/// See `TokenStream::set_pragma_hook`
+type PragmaHook<'a> = Box<dyn FnMut(&Token) + 'a>;
+
 #[allow(unused)]
 impl Token {
+    /// A token with an empty span at the start of its file, which the lexer then sets.
     pub fn new(kind: TokenKind, pos: Position) -> Self {
         Self {
             kind: Box::new(kind),
+            span: Span::new(pos.file_id, 0, 0),
             pos,
+            at_bol: false,
+            has_space: false,
+            spelling: None,
         }
     }
 
+    /// The token as written, for `#`, `##` and `-E`.
+    pub fn spelling(&self) -> String {
+        self.spelling
+            .clone()
+            .unwrap_or_else(|| self.kind.to_string())
+    }
+
     pub fn kind_eq(&self, rhs: &Token) -> bool {
         self.kind == rhs.kind
     }
@@ -171,26 +825,47 @@ impl Token {
 
 #[allow(unused)]
 impl<'a, I: Iterator<Item = Token>> TokenStream<'a, I> {
-    pub fn new(iter: I, input: &'a str) -> Self {
+    pub fn new(iter: I) -> Self {
         Self {
             iter: iter.peekable(),
-            input,
+            pragma_hook: None,
         }
     }
 
-    pub fn expect_number(&mut self) -> isize {
-        let token = self.next();
+    /// Let a later pass react to `#pragma`s, e.g. `#pragma pack`. Pragmas are dropped
+    /// when no hook is set.
+    pub fn set_pragma_hook(&mut self, hook: impl FnMut(&Token) + 'a) {
+        self.pragma_hook = Some(Box::new(hook));
+    }
+
+    /// Hand the pragmas at the head of the stream to the hook.
+    fn skip_pragmas(&mut self) {
+        while let Some(pragma) = self
+            .iter
+            .next_if(|token| matches!(*token.kind, TokenKind::Pragma(_)))
+        {
+            if let Some(hook) = &mut self.pragma_hook {
+                hook(&pragma);
+            }
+        }
+    }
 
-        match token {
-            Some(Token { kind, pos }) => match *kind {
-                TokenKind::Num(num) => num,
-                _ => self.error_at(Some(pos), &format!("number expected but got: {:?}", kind)),
+    pub fn expect_number(&mut self) -> Result<isize, Diagnostic> {
+        match self.next() {
+            Some(token) => match &*token.kind {
+                TokenKind::Num(num, _) => Ok(*num),
+                _ => Err(unexpected_token(&token, "a number")),
             },
-            _ => self.error_at(None, &format!("number expected but got: {:?}", token)),
+            None => Err(self.error_at(
+                ErrorCode::UnexpectedToken,
+                None,
+                "expected a number but the tokens ran out",
+            )),
         }
     }
 
     pub fn peek_kind(&mut self) -> Option<Box<TokenKind>> {
+        self.skip_pragmas();
         self.iter.peek().map(|token| token.kind.clone())
     }
 
@@ -201,54 +876,70 @@ impl<'a, I: Iterator<Item = Token>> TokenStream<'a, I> {
         }
     }
 
-    pub fn error_at(&mut self, pos: impl Into<Option<Position>>, msg: &str) -> ! {
-        let pos: Option<Position> = pos.into();
-        match pos {
-            None => panic!("Passed pos info was None. \n{}", msg),
-            Some(pos) => {
-                let mut splitted = self.input.split('\n');
-                let line = splitted.nth(pos.n_line).unwrap_or_else(|| {
-                    panic!(
-                        "Position is illeagl, pos: {:?}, \n input: {}",
-                        pos, self.input
-                    );
-                });
-
-                eprintln!("{}", line);
-                let mut buffer = String::with_capacity(pos.n_char + 1);
-                for _ in 0..pos.n_char {
-                    buffer.push(' ');
-                }
-                buffer.push('^');
-                eprintln!("{}", buffer);
-                eprintln!("Error: {}", msg);
-                panic!()
-            }
-        }
+    pub fn error_at(
+        &self,
+        code: ErrorCode,
+        span: impl Into<Option<Span>>,
+        msg: &str,
+    ) -> Diagnostic {
+        Diagnostic::error(code, span, msg)
     }
 
-    pub fn expect(&mut self, kind: TokenKind) {
-        let next_token = self.next();
-
-        match next_token {
-            Some(Token { kind: k, pos }) => {
-                if *k != kind {
-                    self.error_at(Some(pos), &format!("expected {:?} but got {:?}", kind, k))
-                }
-            }
-            None => self.error_at(None, &format!("expected {:?} but got None", kind)),
+    pub fn expect(&mut self, kind: TokenKind) -> Result<(), Diagnostic> {
+        match self.next() {
+            Some(token) if *token.kind == kind => Ok(()),
+            Some(token) => Err(unexpected_token(&token, &format!("'{}'", kind))
+                .with_fixit(token.span.shrink_to_start(), kind.to_string())),
+            None => Err(self.error_at(
+                ErrorCode::UnexpectedToken,
+                None,
+                &format!("expected '{}' but the tokens ran out", kind),
+            )),
         }
     }
 
     pub fn peek(&mut self) -> Option<&I::Item> {
+        self.skip_pragmas();
         self.iter.peek()
     }
+
+    /// Take the next token if `func` accepts it.
+    pub fn next_if(&mut self, func: impl FnOnce(&Token) -> bool) -> Option<Token> {
+        self.skip_pragmas();
+        self.iter.next_if(func)
+    }
+}
+
+/// The error for finding `token` where `expected` should be. An invalid token is
+/// reported as such: invalid tokens are only an error once they reach the parser.
+pub fn unexpected_token(token: &Token, expected: &str) -> Diagnostic {
+    if matches!(&*token.kind, TokenKind::Unknown(spelling) if spelling == "=") {
+        return Diagnostic::error(
+            ErrorCode::Assignment,
+            token.span.clone(),
+            "assignment is not supported; did you mean '=='?",
+        )
+        .with_fixit(token.span.clone(), "==");
+    }
+    let (code, msg) = match &*token.kind {
+        TokenKind::Unknown(spelling) => (ErrorCode::InvalidToken, describe_unknown(spelling)),
+        TokenKind::Eof => (
+            ErrorCode::UnexpectedToken,
+            format!("expected {} but reached the end of input", expected),
+        ),
+        kind => (
+            ErrorCode::UnexpectedToken,
+            format!("expected {} but got '{}'", expected, kind),
+        ),
+    };
+    Diagnostic::error(code, token.span.clone(), msg)
 }
 
 impl<I: Iterator<Item = Token>> Iterator for TokenStream<'_, I> {
     type Item = Token;
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.skip_pragmas();
         self.iter.next()
     }
 }
@@ -257,12 +948,18 @@ impl<I: Iterator<Item = Token>> Iterator for TokenStream<'_, I> {
 pub struct Position {
     pub n_char: usize,
     pub n_line: usize,
+    /// The `SourceMap` id of the file this position is in
+    pub file_id: usize,
 }
 
 #[allow(unused)]
 impl Position {
     pub fn new(n_char: usize, n_line: usize) -> Self {
-        Self { n_char, n_line }
+        Self {
+            n_char,
+            n_line,
+            file_id: 0,
+        }
     }
 
     pub fn next_line(&mut self) -> Self {
@@ -285,6 +982,63 @@ impl Position {
     }
 }
 
+/// The byte range `start..end` of the input of the file `file_id` in the `SourceMap`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Span {
+    pub file_id: usize,
+    pub start: usize,
+    pub end: usize,
+    /// Whether the span covers tokens made by a macro expansion, which have the span of
+    /// the invocation rather than of their own text
+    pub from_macro: bool,
+}
+
+impl Span {
+    pub fn new(file_id: usize, start: usize, end: usize) -> Self {
+        Self {
+            file_id,
+            start,
+            end,
+            from_macro: false,
+        }
+    }
+
+    /// The span from the start of `self` to the end of `end`. Stays `self` if `end` is
+    /// in another file or before it, as when a macro expansion crosses an `#include`.
+    pub fn to(&self, end: &Span) -> Span {
+        let mut span = self.clone();
+        if end.file_id == self.file_id && end.end >= self.start {
+            span.end = end.end;
+        }
+        span.from_macro |= end.from_macro;
+        span
+    }
+
+    /// The empty span just past the end of `self`.
+    pub fn shrink_to_end(&self) -> Span {
+        Span {
+            start: self.end,
+            ..self.clone()
+        }
+    }
+
+    /// The empty span just before the start of `self`.
+    pub fn shrink_to_start(&self) -> Span {
+        Span {
+            end: self.start,
+            ..self.clone()
+        }
+    }
+
+    /// The span of a macro invocation, standing for the tokens of its expansion.
+    pub fn expanded(&self) -> Span {
+        Span {
+            from_macro: true,
+            ..self.clone()
+        }
+    }
+}
+
 #[allow(unused)]
 trait Expect {
     type Item;
@@ -310,7 +1064,7 @@ macro_rules! tokens {
 macro_rules! token_poses {
     ( $( ($token_kind:expr, $pos:expr) ),* $(,)? ) => {
         vec![
-            $( Token::new($token_kind, $pos) ),*
+            $( (Box::new($token_kind), $pos) ),*
         ]
     };
 }
@@ -344,15 +1098,16 @@ mod tests {
         assert_eq!(
             lexer
                 .tokenize()
+                .unwrap()
                 .into_iter()
                 .map(|token| token.kind())
                 .collect::<Vec<_>>(),
             token_kinds![
-                TokenKind::Num(1),
+                TokenKind::Num(1, IntType::Int),
                 TokenKind::BinOp(BinOpToken::Plus),
-                TokenKind::Num(4),
+                TokenKind::Num(4, IntType::Int),
                 TokenKind::BinOp(BinOpToken::Minus),
-                TokenKind::Num(909),
+                TokenKind::Num(909, IntType::Int),
                 TokenKind::Eof
             ]
         );
@@ -363,21 +1118,22 @@ mod tests {
         assert_eq!(
             lexer
                 .tokenize()
+                .unwrap()
                 .into_iter()
                 .map(|token| token.kind())
                 .collect::<Vec<_>>(),
             token_kinds![
-                TokenKind::Num(0),
+                TokenKind::Num(0, IntType::Int),
                 TokenKind::BinOp(BinOpToken::Plus),
-                TokenKind::Num(5),
+                TokenKind::Num(5, IntType::Int),
                 TokenKind::BinOp(BinOpToken::Plus),
-                TokenKind::Num(1),
+                TokenKind::Num(1, IntType::Int),
                 TokenKind::BinOp(BinOpToken::Plus),
-                TokenKind::Num(9),
+                TokenKind::Num(9, IntType::Int),
                 TokenKind::BinOp(BinOpToken::Minus),
-                TokenKind::Num(3),
+                TokenKind::Num(3, IntType::Int),
                 TokenKind::BinOp(BinOpToken::Minus),
-                TokenKind::Num(909),
+                TokenKind::Num(909, IntType::Int),
                 TokenKind::Eof
             ]
         );
@@ -388,17 +1144,18 @@ mod tests {
         assert_eq!(
             lexer
                 .tokenize()
+                .unwrap()
                 .into_iter()
                 .map(|token| token.kind())
                 .collect::<Vec<_>>(),
             token_kinds![
                 TokenKind::OpenDelim(DelimToken::Paren),
-                TokenKind::Num(1),
+                TokenKind::Num(1, IntType::Int),
                 TokenKind::BinOp(BinOpToken::Plus),
-                TokenKind::Num(2),
+                TokenKind::Num(2, IntType::Int),
                 TokenKind::CloseDelim(DelimToken::Paren),
                 TokenKind::BinOp(BinOpToken::Mul),
-                TokenKind::Num(3),
+                TokenKind::Num(3, IntType::Int),
                 TokenKind::Eof
             ]
         );
@@ -409,21 +1166,145 @@ mod tests {
         assert_eq!(
             lexer
                 .tokenize()
+                .unwrap()
                 .into_iter()
                 .map(|token| token.kind())
                 .collect::<Vec<_>>(),
             token_kinds![
                 TokenKind::OpenDelim(DelimToken::Paren),
-                TokenKind::Num(1),
+                TokenKind::Num(1, IntType::Int),
                 TokenKind::BinOp(BinOpToken::Plus),
                 TokenKind::OpenDelim(DelimToken::Paren),
-                TokenKind::Num(2),
+                TokenKind::Num(2, IntType::Int),
                 TokenKind::BinOp(BinOpToken::Plus),
-                TokenKind::Num(3),
+                TokenKind::Num(3, IntType::Int),
                 TokenKind::CloseDelim(DelimToken::Paren),
                 TokenKind::CloseDelim(DelimToken::Paren),
                 TokenKind::BinOp(BinOpToken::Mul),
-                TokenKind::Num(4),
+                TokenKind::Num(4, IntType::Int),
+                TokenKind::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_int_literal() {
+        let input = String::from("0x1F 017 0b101 0 42u 42L 42ull 4294967295 0xffffffff");
+        let lexer = Lexer::new(&input);
+
+        assert_eq!(
+            lexer
+                .tokenize()
+                .unwrap()
+                .into_iter()
+                .map(|token| token.kind())
+                .collect::<Vec<_>>(),
+            token_kinds![
+                TokenKind::Num(31, IntType::Int),
+                TokenKind::Num(15, IntType::Int),
+                TokenKind::Num(5, IntType::Int),
+                TokenKind::Num(0, IntType::Int),
+                TokenKind::Num(42, IntType::UInt),
+                TokenKind::Num(42, IntType::Long),
+                TokenKind::Num(42, IntType::ULongLong),
+                TokenKind::Num(4294967295, IntType::Long),
+                TokenKind::Num(4294967295, IntType::UInt),
+                TokenKind::Eof
+            ]
+        );
+
+        assert_eq!(
+            parse_int_literal("0xffffffffffffffff"),
+            Ok((-1, IntType::ULong))
+        );
+        assert_eq!(
+            parse_int_literal("9223372036854775807"),
+            Ok((isize::MAX, IntType::Long))
+        );
+    }
+
+    #[test]
+    fn test_parse_int_literal_error() {
+        assert!(parse_int_literal("99999999999999999999").is_err());
+        assert!(parse_int_literal("9223372036854775808").is_err());
+        assert!(parse_int_literal("089").is_err());
+        assert!(parse_int_literal("0b12").is_err());
+        assert!(parse_int_literal("0x").is_err());
+        assert!(parse_int_literal("12abc").is_err());
+        assert!(parse_int_literal("1lL").is_err());
+        assert!(parse_int_literal("1uu").is_err());
+    }
+
+    #[test]
+    fn test_int_type_common() {
+        use IntType::*;
+        assert_eq!(Int.common(&Long), Long);
+        assert_eq!(Int.common(&UInt), UInt);
+        assert_eq!(UInt.common(&Long), Long);
+        assert_eq!(ULong.common(&LongLong), ULongLong);
+        assert_eq!(LongLong.common(&ULong), ULongLong);
+        assert_eq!(ULongLong.common(&Int), ULongLong);
+    }
+
+    #[test]
+    fn test_tokenize_char_constant() {
+        let kinds = |input: &str| {
+            Lexer::new(input)
+                .tokenize()
+                .unwrap()
+                .into_iter()
+                .map(|token| *token.kind)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            kinds(r"'a' '\n' '\x41' '\101' '\377' 'ab' L'\377' u'\x1234' U'x' L"),
+            vec![
+                TokenKind::Num(97, IntType::Int),
+                TokenKind::Num(10, IntType::Int),
+                TokenKind::Num(65, IntType::Int),
+                TokenKind::Num(65, IntType::Int),
+                TokenKind::Num(-1, IntType::Int),
+                TokenKind::Num(0x6162, IntType::Int),
+                TokenKind::Num(255, IntType::Int),
+                TokenKind::Num(0x1234, IntType::Int),
+                TokenKind::Num(120, IntType::UInt),
+                TokenKind::Ident("L".to_string()),
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            kinds("'' '\\q' 'a\n"),
+            vec![
+                TokenKind::Unknown("''".to_string()),
+                TokenKind::Unknown(r"'\q'".to_string()),
+                TokenKind::Unknown("'a".to_string()),
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            describe_unknown("''"),
+            "no characters in character constant ''"
+        );
+        assert_eq!(describe_unknown(r"'\'"), "missing terminating ' character");
+        assert_eq!(
+            describe_unknown(r"'\x100'"),
+            r"hex escape sequence out of range in character constant '\x100'"
+        );
+        assert_eq!(
+            describe_unknown(r"'\777'"),
+            r"octal escape sequence out of range in character constant '\777'"
+        );
+        assert_eq!(
+            describe_unknown(r"u'\x10000'"),
+            r"hex escape sequence out of range in character constant u'\x10000'"
+        );
+        assert_eq!(
+            kinds(r"L'\x100' L'\777' u'\xffff'"),
+            vec![
+                TokenKind::Num(0x100, IntType::Int),
+                TokenKind::Num(0o777, IntType::Int),
+                TokenKind::Num(0xffff, IntType::Int),
                 TokenKind::Eof
             ]
         );
@@ -434,11 +1315,16 @@ mod tests {
         let input = String::from("1 +1");
         let tokenizer = Lexer::new(&input);
         assert_eq!(
-            tokenizer.tokenize(),
+            tokenizer
+                .tokenize()
+                .unwrap()
+                .into_iter()
+                .map(|token| (token.kind(), token.pos))
+                .collect::<Vec<_>>(),
             token_poses![
-                (TokenKind::Num(1), Position::new(0, 0)),
+                (TokenKind::Num(1, IntType::Int), Position::new(0, 0)),
                 (TokenKind::BinOp(BinOpToken::Plus), Position::new(2, 0)),
-                (TokenKind::Num(1), Position::new(3, 0)),
+                (TokenKind::Num(1, IntType::Int), Position::new(3, 0)),
                 (TokenKind::Eof, Position::new(4, 0))
             ]
         );
@@ -446,15 +1332,189 @@ mod tests {
         let input = String::from("1 +1 \n\t+5");
         let tokenizer = Lexer::new(&input);
         assert_eq!(
-            tokenizer.tokenize(),
+            tokenizer
+                .tokenize()
+                .unwrap()
+                .into_iter()
+                .map(|token| (token.kind(), token.pos))
+                .collect::<Vec<_>>(),
             token_poses![
-                (TokenKind::Num(1), Position::new(0, 0)),
+                (TokenKind::Num(1, IntType::Int), Position::new(0, 0)),
                 (TokenKind::BinOp(BinOpToken::Plus), Position::new(2, 0)),
-                (TokenKind::Num(1), Position::new(3, 0)),
+                (TokenKind::Num(1, IntType::Int), Position::new(3, 0)),
                 (TokenKind::BinOp(BinOpToken::Plus), Position::new(1, 1)),
-                (TokenKind::Num(5), Position::new(2, 1)),
+                (TokenKind::Num(5, IntType::Int), Position::new(2, 1)),
                 (TokenKind::Eof, Position::new(3, 1))
             ]
         );
     }
+
+    #[test]
+    fn test_tokenize_comment() {
+        let input = String::from("1 // 2 + 3\n/ 4 /* 5\n * 6\n */ + 7");
+        let tokenizer = Lexer::new(&input);
+        assert_eq!(
+            tokenizer
+                .tokenize()
+                .unwrap()
+                .into_iter()
+                .map(|token| (token.kind(), token.pos))
+                .collect::<Vec<_>>(),
+            token_poses![
+                (TokenKind::Num(1, IntType::Int), Position::new(0, 0)),
+                (TokenKind::BinOp(BinOpToken::Div), Position::new(0, 1)),
+                (TokenKind::Num(4, IntType::Int), Position::new(2, 1)),
+                (TokenKind::BinOp(BinOpToken::Plus), Position::new(4, 3)),
+                (TokenKind::Num(7, IntType::Int), Position::new(6, 3)),
+                (TokenKind::Eof, Position::new(7, 3))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_line_splice() {
+        let input = String::from("12\\\n34 + \\\n5 // 6 \\\n 7\n8");
+        let tokenizer = Lexer::new(&input);
+        assert_eq!(
+            tokenizer
+                .tokenize()
+                .unwrap()
+                .into_iter()
+                .map(|token| (token.kind(), token.pos))
+                .collect::<Vec<_>>(),
+            token_poses![
+                (TokenKind::Num(1234, IntType::Int), Position::new(0, 0)),
+                (TokenKind::BinOp(BinOpToken::Plus), Position::new(3, 1)),
+                (TokenKind::Num(5, IntType::Int), Position::new(0, 2)),
+                (TokenKind::Num(8, IntType::Int), Position::new(0, 4)),
+                (TokenKind::Eof, Position::new(1, 4))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_unterminated_comment() {
+        let input = String::from("1 /* 2\n");
+        assert_eq!(
+            Lexer::new(&input).tokenize(),
+            Err(Diagnostic::error(
+                ErrorCode::UnterminatedComment,
+                Span::new(0, 2, 4),
+                "unterminated comment"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_token_spans() {
+        // `é` takes two bytes; a spliced token spans the backslash-newline too
+        let input = "é + ab\\\ncd\n  42";
+        let spans: Vec<_> = Lexer::with_file_id(input, 3)
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|token| (token.span.start, token.span.end))
+            .collect();
+        assert_eq!(spans, [(0, 2), (3, 4), (5, 11), (14, 16), (16, 16)]);
+
+        let span = Span::new(0, 2, 4);
+        assert_eq!(span.to(&Span::new(0, 6, 9)), Span::new(0, 2, 9));
+        assert_eq!(span.to(&Span::new(1, 6, 9)), span);
+        assert_eq!(span.shrink_to_end(), Span::new(0, 4, 4));
+    }
+
+    #[test]
+    fn test_tokenize_preprocessing_tokens() {
+        let input = String::from("#include <a.h>\n  F(x, \"s\\\"\") @ 09");
+        let tokens = Lexer::new(&input).tokenize().unwrap();
+
+        assert_eq!(
+            tokens.iter().map(|token| token.kind()).collect::<Vec<_>>(),
+            token_kinds![
+                TokenKind::Pound,
+                TokenKind::Ident("include".to_string()),
+                TokenKind::Lt,
+                TokenKind::Ident("a".to_string()),
+                TokenKind::Unknown(".".to_string()),
+                TokenKind::Ident("h".to_string()),
+                TokenKind::Gt,
+                TokenKind::Ident("F".to_string()),
+                TokenKind::OpenDelim(DelimToken::Paren),
+                TokenKind::Ident("x".to_string()),
+                TokenKind::Comma,
+                TokenKind::Str("s\\\"".to_string()),
+                TokenKind::CloseDelim(DelimToken::Paren),
+                TokenKind::Unknown("@".to_string()),
+                TokenKind::Unknown("09".to_string()),
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| (token.at_bol, token.has_space))
+                .collect::<Vec<_>>(),
+            vec![
+                (true, false),
+                (false, false),
+                (false, true),
+                (false, false),
+                (false, false),
+                (false, false),
+                (false, false),
+                (true, true),
+                (false, false),
+                (false, false),
+                (false, false),
+                (false, true),
+                (false, false),
+                (false, true),
+                (false, true),
+                (false, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_stream_pragma_hook() {
+        let pragma = |name: &str| {
+            let tokens = vec![Token::new(
+                TokenKind::Ident(name.to_string()),
+                Position::new(8, 0),
+            )];
+            Token::new(TokenKind::Pragma(tokens), Position::new(0, 0))
+        };
+        let tokens = vec![
+            pragma("a"),
+            Token::new(TokenKind::Num(1, IntType::Int), Position::new(0, 1)),
+            pragma("b"),
+            Token::new(TokenKind::Eof, Position::new(1, 1)),
+        ];
+
+        let mut seen = Vec::new();
+        {
+            let mut token_stream = TokenStream::new(tokens.into_iter());
+            token_stream.set_pragma_hook(|token: &Token| seen.push(token.kind.to_string()));
+            assert_eq!(token_stream.expect_number(), Ok(1));
+            assert!(token_stream.at_eof());
+        }
+        assert_eq!(seen, vec!["#pragma a", "#pragma b"]);
+    }
+
+    #[test]
+    fn test_unexpected_token() {
+        let token = |kind: TokenKind| Token::new(kind, Position::new(2, 0));
+        assert_eq!(
+            unexpected_token(&token(TokenKind::Unknown("@".to_string())), "a number").message,
+            "stray \"@\" in program"
+        );
+        assert_eq!(
+            unexpected_token(&token(TokenKind::Comma), "a number").message,
+            "expected a number but got ','"
+        );
+        assert_eq!(
+            unexpected_token(&token(TokenKind::Eof), "')'").message,
+            "expected ')' but reached the end of input"
+        );
+    }
 }
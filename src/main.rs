@@ -1,70 +1,567 @@
-use core::panic;
+// Diagnostics are returned by value through `Result` everywhere. Errors are rare
+// enough that their size does not matter.
+#![allow(clippy::result_large_err)]
+
 use std::env;
-use std::ffi::OsString;
+use std::ffi::OsStr;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::BufWriter;
+use std::io::IsTerminal;
 use std::io::Read;
-use std::io::Write;
+use std::mem;
 use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::time::Instant;
 
 mod analyzer;
+mod diagnostic;
+mod error_code;
 mod generator;
+mod json;
 mod lexer;
 mod parser;
+mod preprocessor;
+mod source_map;
+mod toolchain;
+mod warning;
 
+use analyzer::Analyzer;
+use diagnostic::{Diagnostic, DiagnosticFormat, Severity};
+use error_code::ErrorCode;
 use generator::Generator;
+use preprocessor::Preprocessor;
+use source_map::SourceMap;
+use toolchain::{TempFile, io_error};
+use warning::{Warning, WarningOptions};
 
 use crate::lexer::TokenStream;
 
-fn main() -> Result<(), std::io::Error> {
+fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() < 2 {
-        panic!("Please provide a file name as an argument.");
+    if args.get(1).is_some_and(|arg| arg == "--explain") {
+        match explain(args.get(2)) {
+            Ok(explanation) => print!("{}", explanation),
+            Err(err) => exit_with(err),
+        }
+        return;
     }
 
-    let (mut input_file, output_file) = get_file_name(&args).expect("Failed to get file name");
+    let options = parse_args(&args).unwrap_or_else(|err| exit_with(err));
+    let mut source_map = SourceMap::new();
+    let mut diagnostics = Vec::new();
+    let result = build(&options, &mut source_map, &mut diagnostics);
+    let failed = result.is_err();
+    diagnostics.extend(result.err().unwrap_or_default());
 
-    let mut input = String::new();
+    eprint!(
+        "{}",
+        options
+            .diagnostics_format
+            .emit(&diagnostics, &source_map, options.color)
+    );
+    let applied = match options.apply_fixits {
+        true => apply_fixits(&diagnostics, &source_map, &options.inputs),
+        false => Ok(()),
+    };
+    if let Err(err) = applied {
+        eprint!("{}", err.render(&source_map, options.color));
+        process::exit(1);
+    }
+    if failed {
+        process::exit(1);
+    }
+}
 
-    input_file
-        .read_to_string(&mut input)
-        .expect("It must be UTF-8");
+/// Report an error found before there is any source, and exit.
+fn exit_with(err: Diagnostic) -> ! {
+    eprint!(
+        "{}",
+        err.render(&SourceMap::new(), io::stderr().is_terminal())
+    );
+    process::exit(1);
+}
 
-    let tokens = lexer::Lexer::new(&input).tokenize();
-    let mut token_stream = TokenStream::new(tokens.into_iter(), &input);
+/// The explanation of the error code `name` for `--explain`.
+fn explain(name: Option<&String>) -> Result<&'static str, Diagnostic> {
+    let name = name.ok_or_else(|| option_error("--explain requires an error code"))?;
+    ErrorCode::from_name(name)
+        .map(ErrorCode::explanation)
+        .ok_or_else(|| option_error(format!("no explanation for '{}'", name)))
+}
 
-    let parser = parser::Parser::new();
-    let expr = parser.parse_expr(&mut token_stream);
+/// Rewrite the source files among `inputs` with the fix-its of `diagnostics` applied.
+/// Those in other files, such as headers, the predefined macros or stdin, are left for
+/// the user to apply.
+fn apply_fixits(
+    diagnostics: &[Diagnostic],
+    source_map: &SourceMap,
+    inputs: &[String],
+) -> Result<(), Diagnostic> {
+    let fixits: Vec<_> = diagnostics
+        .iter()
+        .flat_map(|diagnostic| &diagnostic.fixits)
+        // a macro expansion has the span of the invocation, not of its own text
+        .filter(|fixit| !fixit.span.from_macro)
+        .collect();
+    let mut file_ids: Vec<_> = fixits.iter().map(|fixit| fixit.span.file_id).collect();
+    file_ids.sort();
+    file_ids.dedup();
 
-    let expr = analyzer::Analyzer::down_expr(expr);
+    let mut written = Vec::new();
+    for file_id in file_ids {
+        let file = source_map.get(file_id);
+        let is_input = inputs.iter().any(|input| {
+            input != "-" && InputKind::of(input) == InputKind::Source && file.path == *input
+        });
+        // an input read again through `#include` is only written once
+        if !is_input || written.contains(&&file.path) {
+            continue;
+        }
+        written.push(&file.path);
+        let in_file: Vec<_> = fixits
+            .iter()
+            .copied()
+            .filter(|fixit| fixit.span.file_id == file_id)
+            .collect();
+        fs::write(
+            &file.path,
+            diagnostic::apply_fixits(&file.input, 0, &in_file),
+        )
+        .map_err(|err| io_error(&format!("cannot write '{}'", file.path.display()), err))?;
+    }
+    Ok(())
+}
 
-    let mut buf_writer = BufWriter::new(output_file);
-    Generator::gen_head(&mut buf_writer, expr)?;
+/// Compile, assemble and link the inputs as far as the mode says. Every input is built
+/// even if one fails, and the warnings that are on and the errors of each are added to
+/// `diagnostics` in turn, in source order as `cc` reports them. Nothing is linked if
+/// any input failed.
+fn build(
+    options: &Options,
+    source_map: &mut SourceMap,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<(), Vec<Diagnostic>> {
+    // reported once all the flags are known, which may turn the warnings off
+    report(options, diagnostics, &options.option_warnings)?;
 
-    buf_writer.flush()?;
+    let mut objects = Vec::new();
+    let mut failed = false;
+    for input in &options.inputs {
+        let start = diagnostics.len();
+        match build_input(options, input, source_map, diagnostics) {
+            Ok(object) => objects.extend(object),
+            Err(errors) => {
+                diagnostics.extend(errors);
+                failed = true;
+            }
+        }
+        // each stage reports on its own, so put them back in source order, with those
+        // that have no place in the source (such as the assembler's) last
+        diagnostics[start..].sort_by_cached_key(|diagnostic| match &diagnostic.span {
+            Some(span) => (false, source_map.order_key(span)),
+            None => (true, Vec::new()),
+        });
+    }
+    if failed {
+        return Err(Vec::new());
+    }
+    if options.mode != Mode::Executable {
+        return Ok(());
+    }
 
-    Ok(())
+    let path = options.output.as_deref().unwrap_or("a.out");
+    let objects: Vec<_> = objects.iter().map(Object::path).collect();
+    toolchain::make_output(path, |path| {
+        toolchain::link(&objects, path, options.verbose)
+    })
+    .map_err(|err| vec![err])
 }
 
-fn get_file_name(args: &[String]) -> Result<(File, File), std::io::Error> {
-    if args.len() < 2 {
-        panic!("Please provide a file name as an argument.");
+/// Build one input as far as the mode says, into where `-o` says or where `cc` would.
+/// Returns the object file to link, if the input is to be linked.
+fn build_input(
+    options: &Options,
+    input: &str,
+    source_map: &mut SourceMap,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<Option<Object>, Vec<Diagnostic>> {
+    let output = |extension| match &options.output {
+        Some(path) => path.clone(),
+        None => toolchain::default_output(input, extension),
+    };
+    let temp_error = |err| vec![io_error("cannot create a temporary file", err)];
+
+    match (InputKind::of(input), options.mode) {
+        (InputKind::Source, mode) => {
+            // each translation unit starts with only the predefined macros
+            let mut preprocessor =
+                Preprocessor::with_source_map(options.include_paths.clone(), mem::take(source_map));
+            preprocessor.set_keep_file_ends(mode == Mode::Preprocess);
+            let compiled = compile(options, input, &mut preprocessor, warnings);
+            *source_map = preprocessor.into_source_map();
+            let compiled = compiled?;
+            match mode {
+                Mode::Preprocess => {
+                    let path = options.output.as_deref().unwrap_or("-");
+                    toolchain::write_output(path, &compiled).map_err(|err| vec![err])?;
+                    Ok(None)
+                }
+                Mode::Assembly => {
+                    toolchain::write_output(&output("s"), &compiled).map_err(|err| vec![err])?;
+                    Ok(None)
+                }
+                Mode::Object | Mode::Executable => {
+                    let assembly = TempFile::new("s").map_err(temp_error)?;
+                    fs::write(assembly.path(), compiled)
+                        .map_err(|err| vec![io_error("cannot write a temporary file", err)])?;
+                    assemble(options, assembly.path(), &output("o"))
+                }
+            }
+        }
+        (InputKind::Assembly, Mode::Object | Mode::Executable) => {
+            assemble(options, Path::new(input), &output("o"))
+        }
+        // as with cc, there is nothing to do with assembly before it is assembled
+        (InputKind::Assembly, _) => Ok(None),
+        (InputKind::Linker, Mode::Executable) => Ok(Some(Object::Input(PathBuf::from(input)))),
+        (InputKind::Linker, _) => {
+            let warning = Diagnostic::warning(
+                Warning::UnusedInput,
+                None,
+                format!(
+                    "{}: linker input file unused because linking not done",
+                    input
+                ),
+            );
+            report(options, warnings, &[warning])?;
+            Ok(None)
+        }
     }
+}
 
-    let input_file_path = Path::new(&args[1]);
-    let input_file = File::open(input_file_path).expect("Failed to open the file");
+/// Assemble `assembly` into `output` with `-c`, or into a temporary object file to
+/// link.
+fn assemble(
+    options: &Options,
+    assembly: &Path,
+    output: &str,
+) -> Result<Option<Object>, Vec<Diagnostic>> {
+    if options.mode == Mode::Object {
+        toolchain::make_output(output, |path| {
+            toolchain::assemble(assembly, path, options.verbose)
+        })
+        .map_err(|err| vec![err])?;
+        return Ok(None);
+    }
+    let object =
+        TempFile::new("o").map_err(|err| vec![io_error("cannot create a temporary file", err)])?;
+    toolchain::assemble(assembly, object.path(), options.verbose).map_err(|err| vec![err])?;
+    Ok(Some(Object::Temp(object)))
+}
 
-    let mut buffer = OsString::with_capacity(input_file_path.as_os_str().len());
-    buffer.push(
-        input_file_path
-            .file_stem()
-            .expect("Failed to get file stem"),
+/// Add the warnings of `new_warnings` that are on to `warnings`. Fails, with no further
+/// errors, if `-Werror` made any of them an error.
+fn report(
+    options: &Options,
+    warnings: &mut Vec<Diagnostic>,
+    new_warnings: &[Diagnostic],
+) -> Result<(), Vec<Diagnostic>> {
+    let start = warnings.len();
+    warnings.extend(
+        new_warnings
+            .iter()
+            .filter_map(|warning| options.warnings.filter(warning.clone())),
     );
-    buffer.push(".s");
+    if warnings[start..]
+        .iter()
+        .any(|warning| warning.severity == Severity::Error)
+    {
+        Err(Vec::new())
+    } else {
+        Ok(())
+    }
+}
+
+/// Compile a source file into assembly, or only preprocess it with `-E`. Fails with the
+/// errors found, several if the parser could recover from them. The warnings that are
+/// on are added to `warnings`, and fail it too if `-Werror` made any of them an error.
+fn compile(
+    options: &Options,
+    input_path: &str,
+    preprocessor: &mut Preprocessor,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let mut input = String::new();
+    let (path, read) = match input_path {
+        "-" => ("<stdin>", io::stdin().read_to_string(&mut input)),
+        path => (
+            path,
+            File::open(path).and_then(|mut input_file| input_file.read_to_string(&mut input)),
+        ),
+    };
+    read.map_err(|err| vec![io_error(&format!("cannot read '{}'", input_path), err)])?;
+
+    let start = Instant::now();
+    let tokens = preprocessor.preprocess(Path::new(path), input);
+    let werror = report(options, warnings, preprocessor.warnings());
+    let tokens = tokens.map_err(|err| vec![err])?;
+    werror?;
+    let preprocessed = Instant::now();
+    if options.verbose {
+        let stats = preprocessor.stats();
+        eprintln!(
+            "includes: {} files read, {} token cache hits, {} skipped by include guard, {} skipped by #pragma once",
+            stats.files_read, stats.cache_hits, stats.guard_skips, stats.once_skips
+        );
+    }
+
+    if options.mode == Mode::Preprocess {
+        let mut output = Vec::new();
+        preprocessor::write_preprocessed(&mut output, &tokens, preprocessor.source_map())
+            .map_err(|err| vec![io_error("cannot write the output", err)])?;
+        if options.verbose {
+            eprintln!("time: preprocess {:?}", preprocessed - start);
+        }
+        return Ok(output);
+    }
+
+    let mut token_stream = TokenStream::new(tokens.into_iter());
+
+    let mut parser = parser::Parser::with_error_limit(options.max_errors);
+    parser.set_scope(preprocessor.macro_names().map(String::from).collect());
+    let expr = parser.parse(&mut token_stream);
+    let mut errors = parser.take_errors();
+    let expr = match expr {
+        Ok(expr) => expr,
+        Err(err) => {
+            errors.push(err);
+            return Err(errors);
+        }
+    };
 
-    let output_file_path = Path::new(buffer.as_os_str());
-    let output_file = File::create(output_file_path).expect("Failed to create the file");
+    // the analyzer checks what could be parsed even if there were syntax errors
+    let werror = report(options, warnings, &Analyzer::check(&expr));
+    let expr = Analyzer::down_expr(expr);
+    let analyzed = Instant::now();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    werror?;
+
+    let mut buf_writer = BufWriter::new(Vec::new());
+    let output = Generator::new()
+        .gen_head(&mut buf_writer, expr)
+        .and_then(|()| buf_writer.into_inner().map_err(|err| err.into_error()))
+        .map_err(|err| vec![io_error("cannot write the output", err)])?;
+    let generated = Instant::now();
+
+    if options.verbose {
+        eprintln!(
+            "time: preprocess {:?}, parse {:?}, codegen {:?}",
+            preprocessed - start,
+            analyzed - preprocessed,
+            generated - analyzed
+        );
+    }
+
+    Ok(output)
+}
+
+/// What is done with an input file, which depends on its extension as with `cc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputKind {
+    /// `.c`, or `-` for stdin, which is compiled
+    Source,
+    /// `.s`, which is assembled
+    Assembly,
+    /// Anything else, such as `.o`, which is given to the linker
+    Linker,
+}
+
+impl InputKind {
+    fn of(path: &str) -> Self {
+        if path == "-" {
+            return InputKind::Source;
+        }
+        match Path::new(path).extension().and_then(OsStr::to_str) {
+            Some("c") => InputKind::Source,
+            Some("s") => InputKind::Assembly,
+            _ => InputKind::Linker,
+        }
+    }
+}
+
+/// An object file to link.
+enum Object {
+    /// One assembled for the link, which is removed after it
+    Temp(TempFile),
+    /// One given on the command line
+    Input(PathBuf),
+}
+
+impl Object {
+    fn path(&self) -> &Path {
+        match self {
+            Object::Temp(temp) => temp.path(),
+            Object::Input(path) => path,
+        }
+    }
+}
+
+/// How far the inputs are built, in the order of the stages. The flag of the earliest stage
+/// wins, as with `cc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Mode {
+    /// `-E`: write the preprocessed source, by default to stdout
+    Preprocess,
+    /// `-S`: write the assembly
+    Assembly,
+    /// `-c`: assemble it into an object file
+    Object,
+    /// Link it into an executable, by default `a.out`
+    Executable,
+}
+
+struct Options {
+    /// The files to build, `-` for a source file read from stdin
+    inputs: Vec<String>,
+    /// `-o FILE`: where to write the output, `-` for stdout. Only allowed with `-c`,
+    /// `-S` or `-E` if a single input makes an output.
+    output: Option<String>,
+    include_paths: Vec<PathBuf>,
+    /// `-v`: report include handling, phase timings and the tools run on stderr
+    verbose: bool,
+    mode: Mode,
+    /// `-fmax-errors=N`: stop after N errors, 0 for no limit
+    max_errors: usize,
+    /// `-W...`: which warnings are reported, and which as errors
+    warnings: WarningOptions,
+    /// The warnings about the `-W` flags themselves
+    option_warnings: Vec<Diagnostic>,
+    /// `--diagnostics-format=text|json|sarif`
+    diagnostics_format: DiagnosticFormat,
+    /// `--apply-fixits`: rewrite the source files with the fix-its of the diagnostics
+    apply_fixits: bool,
+    /// `--color=auto|always|never`: color the diagnostics, by default if stderr is a
+    /// terminal. It is stderr rather than stdout that is checked since the diagnostics
+    /// are written there, so `chimocc -E x.c > x.i` still colors them, as gcc does.
+    color: bool,
+}
+
+/// An error in the command line.
+fn option_error(msg: impl Into<String>) -> Diagnostic {
+    Diagnostic::error(ErrorCode::InvalidOption, None, msg)
+}
+
+fn parse_args(args: &[String]) -> Result<Options, Diagnostic> {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut include_paths = Vec::new();
+    let mut verbose = false;
+    let mut mode = Mode::Executable;
+    let mut max_errors = 20;
+    let mut warnings = WarningOptions::default();
+    let mut option_warnings = Vec::new();
+    let mut diagnostics_format = DiagnosticFormat::default();
+    let mut apply_fixits = false;
+    let mut color = io::stderr().is_terminal();
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-I" {
+            include_paths.push(PathBuf::from(option_value(&mut args, arg, "a directory")?));
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            include_paths.push(PathBuf::from(dir));
+        } else if arg == "-o" {
+            output = Some(option_value(&mut args, arg, "a file name")?);
+        } else if let Some(path) = arg.strip_prefix("-o") {
+            output = Some(path.to_string());
+        } else if arg == "-v" {
+            verbose = true;
+        } else if arg == "-E" {
+            mode = mode.min(Mode::Preprocess);
+        } else if arg == "-S" {
+            mode = mode.min(Mode::Assembly);
+        } else if arg == "-c" {
+            mode = mode.min(Mode::Object);
+        } else if let Some(n) = arg.strip_prefix("-fmax-errors=") {
+            max_errors = n
+                .parse()
+                .map_err(|_| option_error(format!("'{}' is not a number in '{}'", n, arg)))?;
+        } else if let Some(flag) = arg.strip_prefix("-W") {
+            if let Err(msg) = warnings.apply(flag) {
+                option_warnings.push(Diagnostic::warning(
+                    Warning::UnknownWarningOption,
+                    None,
+                    msg,
+                ));
+            }
+        } else if let Some(name) = arg.strip_prefix("--diagnostics-format=") {
+            diagnostics_format = DiagnosticFormat::from_name(name)
+                .ok_or_else(|| option_error("--diagnostics-format must be text, json or sarif"))?;
+        } else if arg == "--apply-fixits" {
+            apply_fixits = true;
+        } else if let Some(when) = arg.strip_prefix("--color=") {
+            color = match when {
+                // the diagnostics go to stderr, whatever stdout is
+                "auto" => io::stderr().is_terminal(),
+                "always" => true,
+                "never" => false,
+                _ => return Err(option_error("--color must be auto, always or never")),
+            };
+        } else if arg.starts_with('-') && arg != "-" {
+            return Err(option_error(format!(
+                "unrecognized command-line option '{}'",
+                arg
+            )));
+        } else {
+            inputs.push(arg.clone());
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err(option_error("no input files"));
+    }
+    let outputs = inputs
+        .iter()
+        .filter(|input| match InputKind::of(input) {
+            InputKind::Source => true,
+            InputKind::Assembly => mode == Mode::Object,
+            InputKind::Linker => false,
+        })
+        .count();
+    if output.is_some() && mode != Mode::Executable && outputs > 1 {
+        return Err(option_error(
+            "cannot specify -o with -c, -S or -E with multiple files",
+        ));
+    }
+
+    Ok(Options {
+        inputs,
+        output,
+        include_paths,
+        verbose,
+        mode,
+        max_errors,
+        warnings,
+        option_warnings,
+        diagnostics_format,
+        apply_fixits,
+        color,
+    })
+}
 
-    Ok((input_file, output_file))
+/// The argument after `option`, which requires `what`.
+fn option_value<'a>(
+    args: &mut impl Iterator<Item = &'a String>,
+    option: &str,
+    what: &str,
+) -> Result<String, Diagnostic> {
+    args.next()
+        .cloned()
+        .ok_or_else(|| option_error(format!("missing {} after '{}'", what, option)))
 }
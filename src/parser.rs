@@ -1,18 +1,276 @@
-use crate::lexer::{BinOpToken, DelimToken, Token, TokenKind, TokenStream};
+use std::cell::RefCell;
 
-pub struct Parser {}
+use crate::diagnostic::{Diagnostic, closest_name};
+use crate::error_code::ErrorCode;
+use crate::lexer::{
+    BinOpToken, DelimToken, IntType, Span, Token, TokenKind, TokenStream, unexpected_token,
+};
+
+pub struct Parser {
+    /// Syntax errors recovered from so far
+    errors: RefCell<Vec<Diagnostic>>,
+    /// The number of errors at which parsing stops, 0 for no limit
+    error_limit: usize,
+    /// The names an undeclared identifier is compared with to suggest what was meant
+    scope: Vec<String>,
+}
 
 #[allow(unused)]
 impl Parser {
+    /// A parser that stops at the first error, as `#if` wants.
     pub fn new() -> Self {
-        Self {}
+        Self::with_error_limit(1)
+    }
+
+    /// A parser that recovers from syntax errors until `error_limit` of them have been
+    /// found. The errors recovered from are returned by `take_errors`.
+    pub fn with_error_limit(error_limit: usize) -> Self {
+        Self {
+            errors: RefCell::new(Vec::new()),
+            error_limit,
+            scope: Vec::new(),
+        }
+    }
+
+    /// Set the names in scope. There are no declarations yet, so these are the macros
+    /// defined at the end of the input: an identifier the parser sees is one that was
+    /// not expanded.
+    pub fn set_scope(&mut self, names: Vec<String>) {
+        self.scope = names;
+    }
+
+    pub fn take_errors(&self) -> Vec<Diagnostic> {
+        self.errors.take()
+    }
+
+    /// Parse the whole input, which must be one expression. The expression is returned
+    /// even if errors were recovered from, with `0` standing in for what could not be
+    /// parsed.
+    pub fn parse<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let expr = self.parse_expr(tokens)?;
+        match tokens.next() {
+            Some(token) if *token.kind != TokenKind::Eof => {
+                self.recover(unexpected_token(&token, "an operator"))?;
+                Self::synchronize(tokens);
+                Ok(expr)
+            }
+            _ => Ok(expr),
+        }
+    }
+
+    /// Record a syntax error so that parsing can go on, or return it as the error to
+    /// stop at once the error limit is reached.
+    fn recover(&self, err: Diagnostic) -> Result<(), Diagnostic> {
+        let mut errors = self.errors.borrow_mut();
+        // a second error at the same token is a consequence of the first
+        if errors.last().is_some_and(|last| last.span == err.span) {
+            return Ok(());
+        }
+        if self.error_limit == 1 {
+            return Err(err);
+        }
+        if self.error_limit != 0 && errors.len() + 1 >= self.error_limit {
+            return Err(err.with_note("too many errors, stopping now"));
+        }
+        errors.push(err);
+        Ok(())
+    }
+
+    /// Skip tokens up to the `)` that closes the current parenthesized expression, or
+    /// to the end of input, where parsing can resume.
+    fn synchronize<I>(tokens: &mut TokenStream<'_, I>)
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let mut depth = 0;
+        while let Some(kind) = tokens.peek_kind() {
+            match *kind {
+                TokenKind::Eof => break,
+                TokenKind::CloseDelim(DelimToken::Paren) if depth == 0 => break,
+                TokenKind::OpenDelim(DelimToken::Paren) => depth += 1,
+                TokenKind::CloseDelim(DelimToken::Paren) => depth -= 1,
+                _ => {}
+            }
+            tokens.next();
+        }
+    }
+
+    pub fn parse_expr<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        self.parse_cond(tokens)
+    }
+
+    pub fn parse_cond<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let cond = self.parse_logor(tokens)?;
+
+        match tokens.peek() {
+            Some(Token { kind, .. }) if **kind == TokenKind::Question => {
+                tokens.next();
+                let then = self.parse_expr(tokens)?;
+                // a missing `:` is taken as written, and the expression after it is
+                // parsed as the third operand
+                match tokens.peek() {
+                    Some(token) if *token.kind == TokenKind::Colon => {
+                        tokens.next();
+                    }
+                    Some(token) => self.recover(
+                        unexpected_token(token, "':'").with_fixit(then.span.shrink_to_end(), ":"),
+                    )?,
+                    None => {}
+                }
+                let els = self.parse_cond(tokens)?;
+                Ok(Expr::new_cond(cond, then, els))
+            }
+            _ => Ok(cond),
+        }
+    }
+
+    pub fn parse_logor<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let mut lhs = self.parse_logand(tokens)?;
+        while let Some(Token { kind, .. }) = tokens.peek() {
+            let op = match &**kind {
+                TokenKind::OrOr => BinOpKind::Or,
+                _ => break,
+            };
+            tokens.next();
+            lhs = Expr::new_binary(op, lhs, self.parse_logand(tokens)?);
+        }
+        Ok(lhs)
+    }
+
+    pub fn parse_logand<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let mut lhs = self.parse_bitor(tokens)?;
+        while let Some(Token { kind, .. }) = tokens.peek() {
+            let op = match &**kind {
+                TokenKind::AndAnd => BinOpKind::And,
+                _ => break,
+            };
+            tokens.next();
+            lhs = Expr::new_binary(op, lhs, self.parse_bitor(tokens)?);
+        }
+        Ok(lhs)
+    }
+
+    pub fn parse_bitor<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let mut lhs = self.parse_bitxor(tokens)?;
+        while let Some(Token { kind, .. }) = tokens.peek() {
+            let op = match &**kind {
+                TokenKind::BinOp(BinOpToken::Or) => BinOpKind::BitOr,
+                _ => break,
+            };
+            tokens.next();
+            lhs = Expr::new_binary(op, lhs, self.parse_bitxor(tokens)?);
+        }
+        Ok(lhs)
+    }
+
+    pub fn parse_bitxor<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let mut lhs = self.parse_bitand(tokens)?;
+        while let Some(Token { kind, .. }) = tokens.peek() {
+            let op = match &**kind {
+                TokenKind::BinOp(BinOpToken::Caret) => BinOpKind::BitXor,
+                _ => break,
+            };
+            tokens.next();
+            lhs = Expr::new_binary(op, lhs, self.parse_bitand(tokens)?);
+        }
+        Ok(lhs)
+    }
+
+    pub fn parse_bitand<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let mut lhs = self.parse_equality(tokens)?;
+        while let Some(Token { kind, .. }) = tokens.peek() {
+            let op = match &**kind {
+                TokenKind::BinOp(BinOpToken::And) => BinOpKind::BitAnd,
+                _ => break,
+            };
+            tokens.next();
+            lhs = Expr::new_binary(op, lhs, self.parse_equality(tokens)?);
+        }
+        Ok(lhs)
     }
 
-    pub fn parse_expr<I>(&self, tokens: &mut TokenStream<'_, I>) -> Expr
+    pub fn parse_equality<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
     where
         I: Clone + Iterator<Item = Token>,
     {
-        let mut lhs = self.parse_mul(tokens);
+        let mut lhs = self.parse_relational(tokens)?;
+        while let Some(Token { kind, .. }) = tokens.peek() {
+            let op = match &**kind {
+                TokenKind::EqEq => BinOpKind::Eq,
+                TokenKind::Ne => BinOpKind::Ne,
+                _ => break,
+            };
+            tokens.next();
+            lhs = Expr::new_binary(op, lhs, self.parse_relational(tokens)?);
+        }
+        Ok(lhs)
+    }
+
+    pub fn parse_relational<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let mut lhs = self.parse_shift(tokens)?;
+        while let Some(Token { kind, .. }) = tokens.peek() {
+            let op = match &**kind {
+                TokenKind::Lt => BinOpKind::Lt,
+                TokenKind::Le => BinOpKind::Le,
+                TokenKind::Gt => BinOpKind::Gt,
+                TokenKind::Ge => BinOpKind::Ge,
+                _ => break,
+            };
+            tokens.next();
+            lhs = Expr::new_binary(op, lhs, self.parse_shift(tokens)?);
+        }
+        Ok(lhs)
+    }
+
+    pub fn parse_shift<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let mut lhs = self.parse_add(tokens)?;
+        while let Some(Token { kind, .. }) = tokens.peek() {
+            let op = match &**kind {
+                TokenKind::BinOp(BinOpToken::Shl) => BinOpKind::Shl,
+                TokenKind::BinOp(BinOpToken::Shr) => BinOpKind::Shr,
+                _ => break,
+            };
+            tokens.next();
+            lhs = Expr::new_binary(op, lhs, self.parse_add(tokens)?);
+        }
+        Ok(lhs)
+    }
+
+    pub fn parse_add<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
+    where
+        I: Clone + Iterator<Item = Token>,
+    {
+        let mut lhs = self.parse_mul(tokens)?;
 
         while let Some(Token { kind, .. }) = tokens.peek() {
             let op = match &**kind {
@@ -21,63 +279,130 @@ impl Parser {
                 _ => break,
             };
             tokens.next();
-            lhs = Expr::new_binary(op, lhs, self.parse_mul(tokens));
+            lhs = Expr::new_binary(op, lhs, self.parse_mul(tokens)?);
         }
-        lhs
+        Ok(lhs)
     }
 
-    pub fn parse_mul<I>(&self, tokens: &mut TokenStream<'_, I>) -> Expr
+    pub fn parse_mul<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
     where
         I: Clone + Iterator<Item = Token>,
     {
-        let mut lhs = self.parse_unary(tokens);
+        let mut lhs = self.parse_unary(tokens)?;
         while let Some(Token { kind, .. }) = tokens.peek() {
             let op = match &**kind {
                 TokenKind::BinOp(BinOpToken::Mul) => BinOpKind::Mul,
                 TokenKind::BinOp(BinOpToken::Div) => BinOpKind::Div,
+                TokenKind::BinOp(BinOpToken::Percent) => BinOpKind::Rem,
                 _ => break,
             };
             tokens.next();
-            lhs = Expr::new_binary(op, lhs, self.parse_unary(tokens));
+            lhs = Expr::new_binary(op, lhs, self.parse_unary(tokens)?);
         }
-        lhs
+        Ok(lhs)
     }
 
-    pub fn parse_unary<I>(&self, tokens: &mut TokenStream<'_, I>) -> Expr
+    pub fn parse_unary<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
     where
         I: Clone + Iterator<Item = Token>,
     {
         match tokens.peek() {
             Some(Token { kind, .. }) => match &**kind {
                 TokenKind::BinOp(BinOpToken::Plus) => {
-                    tokens.next();
-                    Expr::new_unary(UnOp::Plus, self.parse_unary(tokens))
+                    let op = tokens.next().unwrap();
+                    Ok(Expr::new_unary(
+                        UnOp::Plus,
+                        self.parse_unary(tokens)?,
+                        &op.span,
+                    ))
                 }
                 TokenKind::BinOp(BinOpToken::Minus) => {
-                    tokens.next();
-                    Expr::new_unary(UnOp::Minus, self.parse_unary(tokens))
+                    let op = tokens.next().unwrap();
+                    Ok(Expr::new_unary(
+                        UnOp::Minus,
+                        self.parse_unary(tokens)?,
+                        &op.span,
+                    ))
+                }
+                TokenKind::Not => {
+                    let op = tokens.next().unwrap();
+                    Ok(Expr::new_unary(
+                        UnOp::Not,
+                        self.parse_unary(tokens)?,
+                        &op.span,
+                    ))
+                }
+                TokenKind::Tilde => {
+                    let op = tokens.next().unwrap();
+                    Ok(Expr::new_unary(
+                        UnOp::BitNot,
+                        self.parse_unary(tokens)?,
+                        &op.span,
+                    ))
                 }
                 _ => self.parse_primary(tokens),
             },
-            None => panic!("Expected token, but none"),
+            None => Err(tokens.error_at(
+                ErrorCode::UnexpectedToken,
+                None,
+                "expected an expression but the tokens ran out",
+            )),
         }
     }
 
-    pub fn parse_primary<I>(&self, tokens: &mut TokenStream<'_, I>) -> Expr
+    pub fn parse_primary<I>(&self, tokens: &mut TokenStream<'_, I>) -> Result<Expr, Diagnostic>
     where
         I: Clone + Iterator<Item = Token>,
     {
-        match tokens.next() {
-            Some(token) => match *token.kind {
-                TokenKind::Num(num) => Expr::new_num(num),
-                TokenKind::OpenDelim(DelimToken::Paren) => {
-                    let expr = self.parse_expr(tokens);
-                    tokens.expect(TokenKind::CloseDelim(DelimToken::Paren));
-                    expr
+        let Some(token) = tokens.peek() else {
+            return Err(tokens.error_at(
+                ErrorCode::UnexpectedToken,
+                None,
+                "expected an expression but the tokens ran out",
+            ));
+        };
+        match &*token.kind {
+            TokenKind::Num(num, ty) => {
+                let (num, ty) = (*num, *ty);
+                let span = tokens.next().unwrap().span;
+                Ok(Expr::new_num(num, ty, span))
+            }
+            TokenKind::OpenDelim(DelimToken::Paren) => {
+                let open = tokens.next().unwrap();
+                let mut expr = self.parse_expr(tokens)?;
+                let close = TokenKind::CloseDelim(DelimToken::Paren);
+                if let Some(token) = tokens.peek().filter(|token| *token.kind != close) {
+                    self.recover(
+                        unexpected_token(token, "')'").with_fixit(expr.span.shrink_to_end(), ")"),
+                    )?;
+                    Self::synchronize(tokens);
                 }
-                _ => panic!("Expected a number, found {:?}", token.kind),
-            },
-            None => panic!("No more tokens available in parse_primary"),
+                if let Some(close) = tokens.next_if(|token| *token.kind == close) {
+                    expr.span = open.span.to(&close.span);
+                }
+                Ok(expr)
+            }
+            TokenKind::Ident(name) => {
+                let span = token.span.clone();
+                let mut err = Diagnostic::error(
+                    ErrorCode::UndeclaredIdentifier,
+                    span.clone(),
+                    format!("use of undeclared identifier '{}'", name),
+                );
+                if let Some(closest) = closest_name(name, self.scope.iter().map(String::as_str)) {
+                    err.message = format!("{}; did you mean '{}'?", err.message, closest);
+                    err = err.with_fixit(span.clone(), closest);
+                }
+                self.recover(err)?;
+                tokens.next();
+                Ok(Expr::new_num(0, IntType::Int, span))
+            }
+            _ => {
+                let span = token.span.clone();
+                self.recover(unexpected_token(token, "an expression"))?;
+                Self::synchronize(tokens);
+                Ok(Expr::new_num(0, IntType::Int, span))
+            }
         }
     }
 }
@@ -85,39 +410,58 @@ impl Parser {
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct Expr {
     pub kind: ExprKind,
+    pub span: Span,
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum ExprKind {
     Binary(Binary),
-    Num(isize),
+    /// An integer constant and its type
+    Num(isize, IntType),
     Unary(UnOp, Box<Expr>),
+    /// `cond ? then : els`
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum UnOp {
     Plus,
     Minus,
+    /// `!`
+    Not,
+    /// `~`
+    BitNot,
 }
 
 impl Expr {
     pub fn new_binary(kind: BinOpKind, lhs: Expr, rhs: Expr) -> Self {
         Self {
+            span: lhs.span.to(&rhs.span),
             kind: ExprKind::Binary(Binary::new(kind, Box::new(lhs), Box::new(rhs))),
         }
     }
 
-    pub fn new_num(num: isize) -> Self {
+    pub fn new_num(num: isize, ty: IntType, span: Span) -> Self {
         Self {
-            kind: ExprKind::Num(num),
+            kind: ExprKind::Num(num, ty),
+            span,
         }
     }
 
-    pub fn new_unary(kind: UnOp, expr: Expr) -> Self {
+    /// `op_span` is the span of the operator token.
+    pub fn new_unary(kind: UnOp, expr: Expr, op_span: &Span) -> Self {
         Self {
+            span: op_span.to(&expr.span),
             kind: ExprKind::Unary(kind, Box::new(expr)),
         }
     }
+
+    pub fn new_cond(cond: Expr, then: Expr, els: Expr) -> Self {
+        Self {
+            span: cond.span.to(&els.span),
+            kind: ExprKind::Cond(Box::new(cond), Box::new(then), Box::new(els)),
+        }
+    }
 }
 
 // Binary Operation ( e.g. `1 + 2`, `3 - 4` )
@@ -140,20 +484,40 @@ pub enum BinOpKind {
     Sub,
     Mul,
     Div,
+    Rem,
+    /// `&&`
+    And,
+    /// `||`
+    Or,
+    BitXor,
+    BitAnd,
+    BitOr,
+    Shl,
+    Shr,
+    Eq,
+    Lt,
+    Le,
+    Ne,
+    Ge,
+    Gt,
 }
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
+
     use super::*;
     use crate::lexer::{Lexer, TokenStream};
+    use crate::preprocessor::Preprocessor;
+    use crate::source_map::SourceMap;
 
     #[test]
     fn test_parse_expr() {
         let input = "1 + 2 * 3 - 4 / 5";
-        let tokens = Lexer::new(input).tokenize();
-        let mut token_stream = TokenStream::new(tokens.into_iter(), input);
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let mut token_stream = TokenStream::new(tokens.into_iter());
         let parser = Parser::new();
-        let expr = parser.parse_expr(&mut token_stream);
+        let expr = without_spans(parser.parse_expr(&mut token_stream).unwrap());
 
         let expected = bin(
             BinOpKind::Sub,
@@ -164,10 +528,10 @@ mod tests {
         assert_eq!(expr.kind, expected.kind);
 
         let input = "1 * (2 + 3)";
-        let tokens = Lexer::new(input).tokenize();
-        let mut token_stream = TokenStream::new(tokens.into_iter(), input);
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let mut token_stream = TokenStream::new(tokens.into_iter());
         let parser = Parser::new();
-        let expr = parser.parse_expr(&mut token_stream);
+        let expr = without_spans(parser.parse_expr(&mut token_stream).unwrap());
         let expected = bin(BinOpKind::Mul, num(1), bin(BinOpKind::Add, num(2), num(3)));
         assert_eq!(expr.kind, expected.kind);
     }
@@ -175,39 +539,276 @@ mod tests {
     #[test]
     fn test_unary_op() {
         let input = "-10 + 20";
-        let tokens = Lexer::new(input).tokenize();
-        let mut token_stream = TokenStream::new(tokens.into_iter(), input);
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let mut token_stream = TokenStream::new(tokens.into_iter());
         let parser = Parser::new();
-        let expr = parser.parse_expr(&mut token_stream);
+        let expr = without_spans(parser.parse_expr(&mut token_stream).unwrap());
         let expected = bin(BinOpKind::Add, unary(UnOp::Minus, num(10)), num(20));
         assert_eq!(expr.kind, expected.kind);
 
         let input = "-(-10)";
-        let tokens = Lexer::new(input).tokenize();
-        let mut token_stream = TokenStream::new(tokens.into_iter(), input);
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let mut token_stream = TokenStream::new(tokens.into_iter());
         let parser = Parser::new();
-        let expr = parser.parse_expr(&mut token_stream);
+        let expr = without_spans(parser.parse_expr(&mut token_stream).unwrap());
         let expected = unary(UnOp::Minus, unary(UnOp::Minus, num(10)));
         assert_eq!(expr.kind, expected.kind);
 
         let input = "- -10";
-        let tokens = Lexer::new(input).tokenize();
-        let mut token_stream = TokenStream::new(tokens.into_iter(), input);
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let mut token_stream = TokenStream::new(tokens.into_iter());
         let parser = Parser::new();
-        let expr = parser.parse_expr(&mut token_stream);
+        let expr = without_spans(parser.parse_expr(&mut token_stream).unwrap());
         let expected = unary(UnOp::Minus, unary(UnOp::Minus, num(10)));
         assert_eq!(expr.kind, expected.kind);
     }
 
+    #[test]
+    fn test_parse_precedence() {
+        let input = "1 || 2 && 3 | 4 ^ 5 & 6 == 7 < 8 << 9 + 10 % 11";
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let mut token_stream = TokenStream::new(tokens.into_iter());
+        let parser = Parser::new();
+        let expr = without_spans(parser.parse_expr(&mut token_stream).unwrap());
+
+        let expected = bin(
+            BinOpKind::Or,
+            num(1),
+            bin(
+                BinOpKind::And,
+                num(2),
+                bin(
+                    BinOpKind::BitOr,
+                    num(3),
+                    bin(
+                        BinOpKind::BitXor,
+                        num(4),
+                        bin(
+                            BinOpKind::BitAnd,
+                            num(5),
+                            bin(
+                                BinOpKind::Eq,
+                                num(6),
+                                bin(
+                                    BinOpKind::Lt,
+                                    num(7),
+                                    bin(
+                                        BinOpKind::Shl,
+                                        num(8),
+                                        bin(
+                                            BinOpKind::Add,
+                                            num(9),
+                                            bin(BinOpKind::Rem, num(10), num(11)),
+                                        ),
+                                    ),
+                                ),
+                            ),
+                        ),
+                    ),
+                ),
+            ),
+        );
+        assert_eq!(expr.kind, expected.kind);
+
+        let input = "1 ? 2 : 3 ? !4 : ~5";
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let mut token_stream = TokenStream::new(tokens.into_iter());
+        let parser = Parser::new();
+        let expr = without_spans(parser.parse_expr(&mut token_stream).unwrap());
+        let expected = Expr::new_cond(
+            num(1),
+            num(2),
+            Expr::new_cond(
+                num(3),
+                unary(UnOp::Not, num(4)),
+                unary(UnOp::BitNot, num(5)),
+            ),
+        );
+        assert_eq!(expr.kind, expected.kind);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let message = |input: &str| parse(input).unwrap_err().message;
+        assert_eq!(
+            message("1 +"),
+            "expected an expression but reached the end of input"
+        );
+        assert_eq!(message("(1"), "expected ')' but reached the end of input");
+        assert_eq!(message("1 ? 2 3"), "expected ':' but got '3'");
+        assert_eq!(message("1 2"), "expected an operator but got '2'");
+        assert_eq!(message("1 @"), "stray \"@\" in program");
+        assert_eq!(
+            parse("1 +\n  )").unwrap_err().span,
+            Some(Span::new(0, 6, 7))
+        );
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let input = "(1 + ) * (2 @ 3) + (4 5";
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        let parser = Parser::with_error_limit(0);
+        let expr = parser
+            .parse(&mut TokenStream::new(tokens.clone().into_iter()))
+            .map(without_spans)
+            .unwrap();
+        let expected = bin(
+            BinOpKind::Add,
+            bin(BinOpKind::Mul, bin(BinOpKind::Add, num(1), num(0)), num(2)),
+            num(4),
+        );
+        assert_eq!(expr.kind, expected.kind);
+        let messages: Vec<_> = parser
+            .take_errors()
+            .into_iter()
+            .map(|err| err.message)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "expected an expression but got ')'",
+                "stray \"@\" in program",
+                "expected ')' but got '5'"
+            ]
+        );
+
+        // the error that reaches the limit is returned
+        let parser = Parser::with_error_limit(2);
+        let err = parser
+            .parse(&mut TokenStream::new(tokens.into_iter()))
+            .unwrap_err();
+        assert_eq!(err.message, "stray \"@\" in program");
+        assert_eq!(err.notes, ["too many errors, stopping now"]);
+        assert_eq!(parser.take_errors().len(), 1);
+
+        // one error for a missing operand at the end, not another for the missing `)`
+        let tokens = Lexer::new("(1 +").tokenize().unwrap();
+        let parser = Parser::with_error_limit(0);
+        parser
+            .parse(&mut TokenStream::new(tokens.into_iter()))
+            .unwrap();
+        assert_eq!(parser.take_errors().len(), 1);
+    }
+
+    #[test]
+    fn test_fixits() {
+        let fixit = |input: &str, scope: &[&str]| {
+            let tokens = Lexer::new(input).tokenize().unwrap();
+            let mut parser = Parser::with_error_limit(0);
+            parser.set_scope(scope.iter().map(|name| name.to_string()).collect());
+            parser
+                .parse(&mut TokenStream::new(tokens.into_iter()))
+                .unwrap();
+            let err = parser.take_errors().remove(0);
+            let fixit = &err.fixits[0];
+            (err.message, fixit.span.clone(), fixit.replacement.clone())
+        };
+
+        assert_eq!(
+            fixit("(1 + 2 3", &[]),
+            (
+                "expected ')' but got '3'".to_string(),
+                Span::new(0, 6, 6),
+                ")".to_string()
+            )
+        );
+        assert_eq!(fixit("1 ? 2 3", &[]).1, Span::new(0, 5, 5));
+        assert_eq!(
+            fixit("1 = 1", &[]),
+            (
+                "assignment is not supported; did you mean '=='?".to_string(),
+                Span::new(0, 2, 3),
+                "==".to_string()
+            )
+        );
+        assert_eq!(
+            fixit("VALEU + 1", &["VALUE", "OTHER"]),
+            (
+                "use of undeclared identifier 'VALEU'; did you mean 'VALUE'?".to_string(),
+                Span::new(0, 0, 5),
+                "VALUE".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_no_fixits_in_macro_expansions() {
+        let error = |input: &str| {
+            let tokens = Preprocessor::with_source_map(Vec::new(), SourceMap::new())
+                .preprocess(Path::new("test.c"), input.to_string())
+                .unwrap();
+            let parser = Parser::with_error_limit(0);
+            let result = parser.parse(&mut TokenStream::new(tokens.into_iter()));
+            parser
+                .take_errors()
+                .into_iter()
+                .chain(result.err())
+                .next()
+                .unwrap()
+        };
+
+        // the `=` is in the body of SET, not where SET is used
+        let err = error("#define SET =\n1 SET 1");
+        assert_eq!(
+            err.message,
+            "assignment is not supported; did you mean '=='?"
+        );
+        assert!(err.fixits.is_empty());
+        // the `)` is missing from the body of OPEN, not after `3`
+        let err = error("#define OPEN (1 + 2\nOPEN * 3");
+        assert_eq!(err.message, "expected ')' but reached the end of input");
+        assert!(err.fixits.is_empty());
+        // a fix-it for the text around an expansion is kept
+        let err = error("#define ONE 1\n1 = ONE");
+        assert_eq!(err.fixits[0].span, Span::new(1, 16, 17));
+    }
+
+    #[test]
+    fn test_expr_spans() {
+        let expr = parse("-(1 + 2) * 3").unwrap();
+        assert_eq!(expr.span, Span::new(0, 0, 12));
+        let ExprKind::Binary(Binary { lhs, rhs, .. }) = expr.kind else {
+            panic!("not a binary expression");
+        };
+        assert_eq!(lhs.span, Span::new(0, 0, 8));
+        assert_eq!(rhs.span, Span::new(0, 11, 12));
+        let ExprKind::Unary(_, operand) = lhs.kind else {
+            panic!("not a unary expression");
+        };
+        assert_eq!(operand.span, Span::new(0, 1, 8));
+    }
+
+    fn parse(input: &str) -> Result<Expr, Diagnostic> {
+        let tokens = Lexer::new(input).tokenize().unwrap();
+        Parser::new().parse(&mut TokenStream::new(tokens.into_iter()))
+    }
+
+    /// `expr` with every span cleared, to compare it with an expression built by hand.
+    fn without_spans(expr: Expr) -> Expr {
+        match expr.kind {
+            ExprKind::Binary(Binary { kind, lhs, rhs }) => {
+                bin(kind, without_spans(*lhs), without_spans(*rhs))
+            }
+            ExprKind::Num(n, ty) => Expr::new_num(n, ty, Span::default()),
+            ExprKind::Unary(op, operand) => unary(op, without_spans(*operand)),
+            ExprKind::Cond(cond, then, els) => Expr::new_cond(
+                without_spans(*cond),
+                without_spans(*then),
+                without_spans(*els),
+            ),
+        }
+    }
+
     fn bin(op: BinOpKind, lhs: Expr, rhs: Expr) -> Expr {
         Expr::new_binary(op, lhs, rhs)
     }
 
     fn num(n: isize) -> Expr {
-        Expr::new_num(n)
+        Expr::new_num(n, IntType::Int, Span::default())
     }
 
     fn unary(op: UnOp, expr: Expr) -> Expr {
-        Expr::new_unary(op, expr)
+        Expr::new_unary(op, expr, &Span::default())
     }
 }
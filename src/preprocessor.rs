@@ -0,0 +1,2454 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::analyzer::Analyzer;
+use crate::diagnostic::{Diagnostic, closest_name};
+use crate::error_code::ErrorCode;
+use crate::lexer::{DelimToken, IntType, Lexer, Position, Span, Token, TokenKind, TokenStream};
+use crate::parser::Parser;
+use crate::source_map::SourceMap;
+use crate::warning::Warning;
+
+/// Maximum nesting depth of `#include`
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+/// Macros every translation unit starts with. `__DATE__` and `__TIME__` are added to
+/// these, and `__FILE__`, `__LINE__` and `__COUNTER__` are expanded by
+/// `Preprocessor::dynamic_macro`. `__GNUC__` is not defined, since chimocc does not
+/// accept GNU C, but the extensions glibc's headers use are defined away.
+const PREDEFINED_MACROS: &str = "\
+#define __chimocc__ 1
+#define __STDC__ 1
+#define __STDC_VERSION__ 201112L
+#define __STDC_HOSTED__ 1
+#define __STDC_NO_ATOMICS__ 1
+#define __STDC_NO_COMPLEX__ 1
+#define __STDC_NO_THREADS__ 1
+#define __STDC_NO_VLA__ 1
+#define __x86_64__ 1
+#define __x86_64 1
+#define __amd64__ 1
+#define __amd64 1
+#define __LP64__ 1
+#define _LP64 1
+#define __linux__ 1
+#define __linux 1
+#define __gnu_linux__ 1
+#define __unix__ 1
+#define __unix 1
+#define __ELF__ 1
+#define __CHAR_BIT__ 8
+#define __SIZEOF_SHORT__ 2
+#define __SIZEOF_INT__ 4
+#define __SIZEOF_LONG__ 8
+#define __SIZEOF_LONG_LONG__ 8
+#define __SIZEOF_POINTER__ 8
+#define __SIZEOF_SIZE_T__ 8
+#define __SCHAR_MAX__ 127
+#define __SHRT_MAX__ 32767
+#define __INT_MAX__ 2147483647
+#define __LONG_MAX__ 9223372036854775807L
+#define __LONG_LONG_MAX__ 9223372036854775807LL
+#define __SIZE_TYPE__ unsigned long
+#define __PTRDIFF_TYPE__ long
+#define __WCHAR_TYPE__ int
+#define __ORDER_LITTLE_ENDIAN__ 1234
+#define __ORDER_BIG_ENDIAN__ 4321
+#define __BYTE_ORDER__ __ORDER_LITTLE_ENDIAN__
+#define __extension__
+#define __attribute__(...)
+#define __asm__(...)
+#define __asm(...)
+#define __restrict restrict
+#define __restrict__ restrict
+#define __inline inline
+#define __inline__ inline
+#define __signed__ signed
+#define __const const
+#define __volatile__ volatile
+#define __alignof__ _Alignof
+";
+
+/// Where chimocc's own headers appear to be, searched after the `-I` directories
+const BUILTIN_INCLUDE_DIR: &str = "<chimocc>/include";
+
+/// chimocc's own versions of the headers that belong to the compiler rather than libc
+const BUILTIN_HEADERS: [(&str, &str); 4] = [
+    ("stddef.h", include_str!("../include/stddef.h")),
+    ("stdarg.h", include_str!("../include/stdarg.h")),
+    ("stdbool.h", include_str!("../include/stdbool.h")),
+    ("float.h", include_str!("../include/float.h")),
+];
+
+/// The directives `directive` runs, which a misspelt one is compared with
+const DIRECTIVES: [&str; 14] = [
+    "define",
+    "undef",
+    "include",
+    "include_next",
+    "if",
+    "ifdef",
+    "ifndef",
+    "elif",
+    "else",
+    "endif",
+    "pragma",
+    "error",
+    "warning",
+    "line",
+];
+
+/// Searched last for `#include`, in this order, like gcc on x86-64 Linux
+const SYSTEM_INCLUDE_DIRS: [&str; 3] = [
+    "/usr/local/include",
+    "/usr/include/x86_64-linux-gnu",
+    "/usr/include",
+];
+
+pub struct Preprocessor {
+    macros: HashMap<String, Macro>,
+    include_paths: Vec<PathBuf>,
+    source_map: SourceMap,
+    /// Tokens of every file read so far, by canonical path, so that including a file
+    /// again does not read and lex it again
+    token_cache: HashMap<PathBuf, Vec<Token>>,
+    /// The guard macro of each file that has the `#ifndef X / #define X / #endif` form
+    include_guards: HashMap<PathBuf, String>,
+    /// Files that contained `#pragma once`
+    pragma_once: HashSet<PathBuf>,
+    stats: IncludeStats,
+    /// The files being preprocessed, the innermost `#include` last
+    files: Vec<FileState>,
+    /// The next value of `__COUNTER__`
+    counter: Cell<usize>,
+    /// Warnings reported so far, e.g. by `#warning`
+    warnings: Vec<Diagnostic>,
+    /// Whether the `Eof` of each included file is kept, see `set_keep_file_ends`
+    keep_file_ends: bool,
+}
+
+/// How `#include`s were served, for the verbose report
+#[derive(Debug, Default, Clone)]
+pub struct IncludeStats {
+    /// Files read from disk and lexed
+    pub files_read: usize,
+    /// Includes served from the token cache
+    pub cache_hits: usize,
+    /// Includes skipped because the file's include guard was defined
+    pub guard_skips: usize,
+    /// Includes skipped because of `#pragma once`
+    pub once_skips: usize,
+}
+
+struct FileState {
+    file_id: usize,
+    /// The index in `search_dirs` of the directory the file was found in, where
+    /// `#include_next` goes on from
+    search_index: Option<usize>,
+    /// `#if` groups of this file that are still open, the innermost last
+    conds: Vec<CondIncl>,
+}
+
+/// An `#if`, `#ifdef` or `#ifndef` group waiting for its `#endif`
+struct CondIncl {
+    ctx: CondCtx,
+    /// Whether one of the branches seen so far has been taken
+    included: bool,
+    /// The `#if` that opened the group
+    span: Span,
+}
+
+#[derive(PartialEq, Eq)]
+enum CondCtx {
+    Then,
+    Elif,
+    Else,
+}
+
+/// Name of the parameter that collects the variable arguments
+const VA_ARGS: &str = "__VA_ARGS__";
+
+#[derive(Debug, Clone)]
+struct Macro {
+    /// Parameter names, `None` for an object-like macro. The last one is
+    /// `__VA_ARGS__` for a variadic macro.
+    params: Option<Vec<String>>,
+    is_variadic: bool,
+    body: Vec<Token>,
+    /// The macro name in its `#define`
+    span: Span,
+}
+
+/// One element of a replacement list while `#` and `##` are applied
+enum Replacement {
+    Token(PpToken),
+    /// Stands for an empty argument, so that pasting it is a no-op (C11 6.10.3.3p2)
+    Placemarker,
+}
+
+/// A token with its hide set: the names of the macros whose expansion produced it.
+/// Those macros are not expanded again when the token is rescanned (C11 6.10.3.4p2).
+#[derive(Debug, Clone)]
+struct PpToken {
+    token: Token,
+    hideset: HashSet<String>,
+}
+
+impl PpToken {
+    fn new(token: Token) -> Self {
+        Self {
+            token,
+            hideset: HashSet::new(),
+        }
+    }
+}
+
+impl Preprocessor {
+    /// A preprocessor that adds the files it reads to `source_map`, so that the
+    /// translation units of one compilation share it and their file ids are unique.
+    pub fn with_source_map(include_paths: Vec<PathBuf>, source_map: SourceMap) -> Self {
+        let mut preprocessor = Self {
+            macros: HashMap::new(),
+            include_paths,
+            source_map,
+            token_cache: HashMap::new(),
+            include_guards: HashMap::new(),
+            pragma_once: HashSet::new(),
+            stats: IncludeStats::default(),
+            files: Vec::new(),
+            counter: Cell::new(0),
+            warnings: Vec::new(),
+            keep_file_ends: false,
+        };
+
+        let (date, time) = date_time();
+        let predefined = format!(
+            "{}#define __DATE__ \"{}\"\n#define __TIME__ \"{}\"\n",
+            PREDEFINED_MACROS, date, time
+        );
+        preprocessor
+            .preprocess(Path::new("<built-in>"), predefined)
+            .expect("the predefined macros are valid");
+
+        preprocessor
+    }
+
+    /// Keep the `Eof` of each included file in the output, moved to the `#include`, so
+    /// that `write_preprocessed` can tell where the file ends and which line follows.
+    pub fn set_keep_file_ends(&mut self, keep: bool) {
+        self.keep_file_ends = keep;
+    }
+
+    /// The files read so far, which the positions of the output tokens refer to.
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    pub fn into_source_map(self) -> SourceMap {
+        self.source_map
+    }
+
+    pub fn stats(&self) -> &IncludeStats {
+        &self.stats
+    }
+
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    /// The names of the macros defined now.
+    pub fn macro_names(&self) -> impl Iterator<Item = &str> {
+        self.macros.keys().map(String::as_str)
+    }
+
+    /// Lex `input` read from `path`, then run directives and expand macros in it.
+    pub fn preprocess(&mut self, path: &Path, input: String) -> Result<Vec<Token>, Diagnostic> {
+        let file_id = self.source_map.add(path, input);
+        let tokens =
+            Lexer::with_file_id(&self.source_map.get(file_id).input, file_id).tokenize()?;
+        self.preprocess_tokens(file_id, None, tokens)
+    }
+
+    fn preprocess_tokens(
+        &mut self,
+        file_id: usize,
+        search_index: Option<usize>,
+        tokens: Vec<Token>,
+    ) -> Result<Vec<Token>, Diagnostic> {
+        self.files.push(FileState {
+            file_id,
+            search_index,
+            conds: Vec::new(),
+        });
+
+        let mut pending: VecDeque<PpToken> = tokens.into_iter().map(PpToken::new).collect();
+        let mut output = Vec::new();
+
+        while let Some(pp_token) = pending.pop_front() {
+            match &*pp_token.token.kind {
+                TokenKind::Pound if pp_token.token.at_bol => {
+                    self.directive(pp_token.token, &mut pending, &mut output)?
+                }
+                _ => {
+                    if !self.expand_macro(&pp_token, &mut pending)? {
+                        output.push(pp_token.token);
+                    }
+                }
+            }
+        }
+
+        if let Some(cond) = self.files.last().and_then(|file| file.conds.last()) {
+            return Err(self.error_at(
+                ErrorCode::UnterminatedConditional,
+                &cond.span,
+                "unterminated conditional directive",
+            ));
+        }
+        self.files.pop();
+        Ok(output)
+    }
+
+    fn directive(
+        &mut self,
+        pound: Token,
+        pending: &mut VecDeque<PpToken>,
+        output: &mut Vec<Token>,
+    ) -> Result<(), Diagnostic> {
+        let line = Self::take_line(pending);
+
+        // `#` alone on a line is the null directive
+        let Some(name) = line.first() else {
+            return Ok(());
+        };
+        match &*name.token.kind {
+            TokenKind::Ident(name) if name == "define" => self.define(&pound, &line[1..])?,
+            TokenKind::Ident(name) if name == "undef" => {
+                let name = self.expect_macro_name(&pound, &line[1..])?;
+                self.macros.remove(&name);
+            }
+            TokenKind::Ident(name) if name == "include" || name == "include_next" => {
+                self.include(&pound, line[1..].to_vec(), name == "include_next", output)?
+            }
+            TokenKind::Ident(name) if name == "if" => {
+                let included = self.eval_condition(&pound, line[1..].to_vec())?;
+                self.push_cond(&pound, included, pending);
+            }
+            TokenKind::Ident(name) if name == "ifdef" || name == "ifndef" => {
+                let macro_name = self.expect_macro_name(&pound, &line[1..])?;
+                let included = self.is_defined(&macro_name) == (name == "ifdef");
+                self.push_cond(&pound, included, pending);
+            }
+            TokenKind::Ident(name) if name == "elif" => {
+                let cond = self.current_cond(&pound, "#elif without #if")?;
+                if cond.ctx == CondCtx::Else {
+                    let err = Diagnostic::error(
+                        ErrorCode::UnbalancedConditional,
+                        pound.span.clone(),
+                        "#elif after #else",
+                    );
+                    return Err(err.with_label(cond.span.clone(), "the conditional began here"));
+                }
+                cond.ctx = CondCtx::Elif;
+                // a later branch is not even evaluated once one has been taken
+                if cond.included || !self.eval_condition(&pound, line[1..].to_vec())? {
+                    Self::skip_cond_incl(pending);
+                } else {
+                    self.current_cond(&pound, "#elif without #if")?.included = true;
+                }
+            }
+            TokenKind::Ident(name) if name == "else" => {
+                let cond = self.current_cond(&pound, "#else without #if")?;
+                if cond.ctx == CondCtx::Else {
+                    let err = Diagnostic::error(
+                        ErrorCode::UnbalancedConditional,
+                        pound.span.clone(),
+                        "#else after #else",
+                    );
+                    return Err(err.with_label(cond.span.clone(), "the conditional began here"));
+                }
+                cond.ctx = CondCtx::Else;
+                if cond.included {
+                    Self::skip_cond_incl(pending);
+                }
+            }
+            TokenKind::Ident(name) if name == "pragma" => self.pragma(&pound, &line[1..], output),
+            TokenKind::Ident(name) if name == "error" => {
+                return Err(self.error_at(
+                    ErrorCode::ErrorDirective,
+                    &pound.span,
+                    &format!("#error {}", Self::spell(&line[1..])),
+                ));
+            }
+            TokenKind::Ident(name) if name == "warning" => self.warnings.push(Diagnostic::warning(
+                Warning::Cpp,
+                pound.span.clone(),
+                format!("#warning {}", Self::spell(&line[1..])),
+            )),
+            TokenKind::Ident(name) if name == "line" => {
+                let operands = self.expand_all(line[1..].to_vec())?;
+                self.line(&pound, &line, &operands, false)?;
+            }
+            TokenKind::Ident(name) if name == "endif" => {
+                self.current_cond(&pound, "#endif without #if")?;
+                self.files.last_mut().unwrap().conds.pop();
+            }
+            // a line marker `# 4 "file.c"` as written by `-E`, which is `#line` in effect
+            TokenKind::Num(..) => self.line(&pound, &line, &line, true)?,
+            _ => {
+                let span = &name.token.span;
+                let mut err = self.error_at(
+                    ErrorCode::InvalidDirective,
+                    span,
+                    &format!("invalid preprocessing directive #{}", name.token.kind),
+                );
+                let suggestion = match &*name.token.kind {
+                    TokenKind::Ident(name) => closest_name(name, DIRECTIVES),
+                    _ => None,
+                };
+                if let Some(directive) = suggestion {
+                    err.message = format!("{}; did you mean #{}?", err.message, directive);
+                    err = err.with_fixit(span.clone(), directive);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// `#pragma once` is handled here; other pragmas are passed on in the output as a
+    /// `TokenKind::Pragma` for `-E` and the later passes.
+    fn pragma(&mut self, pound: &Token, line: &[PpToken], output: &mut Vec<Token>) {
+        match line {
+            [PpToken { token, .. }] if *token.kind == TokenKind::Ident("once".to_string()) => {
+                let path = &self.source_map.get(token.pos.file_id).path;
+                let key = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+                self.pragma_once.insert(key);
+            }
+            _ => {
+                let tokens = line.iter().map(|pp_token| pp_token.token.clone()).collect();
+                let mut pragma = Token::new(TokenKind::Pragma(tokens), pound.pos.clone());
+                pragma.span = line
+                    .last()
+                    .map_or(pound.span.clone(), |last| pound.span.to(&last.token.span));
+                pragma.at_bol = true;
+                output.push(pragma);
+            }
+        }
+    }
+
+    /// `#line N "file"`, or the line marker `# N "file" flags...`, which takes its
+    /// operands without macro expansion and may be followed by flags.
+    fn line(
+        &mut self,
+        pound: &Token,
+        line: &[PpToken],
+        operands: &[PpToken],
+        is_marker: bool,
+    ) -> Result<(), Diagnostic> {
+        let directive = if is_marker { "line marker" } else { "#line" };
+        let presumed_line = match operands.first().map(|pp_token| &*pp_token.token.kind) {
+            Some(TokenKind::Num(n, _)) if (0..=2147483647).contains(n) && (*n > 0 || is_marker) => {
+                *n as usize
+            }
+            Some(kind) => {
+                return Err(self.error_at(
+                    ErrorCode::InvalidLineDirective,
+                    &operands[0].token.span,
+                    &format!("\"{}\" after {} is not a positive integer", kind, directive),
+                ));
+            }
+            None => {
+                return Err(self.error_at(
+                    ErrorCode::InvalidLineDirective,
+                    &pound.span,
+                    &format!("{} requires a line number", directive),
+                ));
+            }
+        };
+
+        let path = match operands.get(1).map(|pp_token| &*pp_token.token.kind) {
+            Some(TokenKind::Str(name)) => Some(PathBuf::from(unescape(name))),
+            Some(kind) => {
+                return Err(self.error_at(
+                    ErrorCode::InvalidLineDirective,
+                    &operands[1].token.span,
+                    &format!("invalid filename \"{}\"", kind),
+                ));
+            }
+            None => None,
+        };
+        let extra = operands
+            .get(2)
+            .filter(|pp_token| !is_marker || !matches!(*pp_token.token.kind, TokenKind::Num(..)));
+        if let Some(extra) = extra {
+            self.warnings.push(Diagnostic::warning(
+                Warning::ExtraTokens,
+                extra.token.span.clone(),
+                format!("extra tokens at end of {}", directive),
+            ));
+        }
+
+        // the directive applies from the line after its last (physical) line
+        let last = line
+            .last()
+            .map_or(&pound.pos, |pp_token| &pp_token.token.pos);
+        self.source_map
+            .add_line_directive(pound.pos.file_id, last.n_line + 1, presumed_line, path);
+        Ok(())
+    }
+
+    fn push_cond(&mut self, pound: &Token, included: bool, pending: &mut VecDeque<PpToken>) {
+        self.files.last_mut().unwrap().conds.push(CondIncl {
+            ctx: CondCtx::Then,
+            included,
+            span: pound.span.clone(),
+        });
+        if !included {
+            Self::skip_cond_incl(pending);
+        }
+    }
+
+    /// The innermost open `#if` group of the current file.
+    fn current_cond(&mut self, pound: &Token, msg: &str) -> Result<&mut CondIncl, Diagnostic> {
+        if self.files.last().is_none_or(|file| file.conds.is_empty()) {
+            return Err(self.error_at(ErrorCode::UnbalancedConditional, &pound.span, msg));
+        }
+        Ok(self.files.last_mut().unwrap().conds.last_mut().unwrap())
+    }
+
+    /// Drop the tokens of a group that is not taken, up to the `#elif`, `#else` or
+    /// `#endif` that ends it. Nested conditionals are skipped as a whole, and nothing
+    /// inside is expanded or checked.
+    fn skip_cond_incl(pending: &mut VecDeque<PpToken>) {
+        let mut depth = 0;
+        while let Some(pp_token) = pending.front() {
+            if *pp_token.token.kind == TokenKind::Eof {
+                break;
+            }
+            match Self::directive_name(pending) {
+                Some("if" | "ifdef" | "ifndef") => depth += 1,
+                Some("elif" | "else" | "endif") if depth == 0 => break,
+                Some("endif") => depth -= 1,
+                _ => {}
+            }
+            pending.pop_front();
+        }
+    }
+
+    /// The name of the directive if `pending` starts with one.
+    fn directive_name(pending: &VecDeque<PpToken>) -> Option<&str> {
+        let pound = pending.front()?;
+        let name = pending.get(1)?;
+        match (&*pound.token.kind, &*name.token.kind) {
+            (TokenKind::Pound, TokenKind::Ident(name))
+                if pound.token.at_bol && !name.is_empty() =>
+            {
+                (!pending[1].token.at_bol).then_some(name.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
+            || matches!(
+                name,
+                "__has_include" | "__FILE__" | "__LINE__" | "__COUNTER__"
+            )
+    }
+
+    /// Evaluate the controlling expression of `#if` or `#elif` with the parser's
+    /// expression grammar.
+    fn eval_condition(&self, pound: &Token, line: Vec<PpToken>) -> Result<bool, Diagnostic> {
+        let line = self.replace_defined(pound, line)?;
+        let mut tokens = self
+            .expand_all(line)?
+            .into_iter()
+            .map(|pp_token| {
+                let mut token = pp_token.token;
+                // identifiers that are left after expansion evaluate to 0
+                if let TokenKind::Ident(_) = *token.kind {
+                    token.kind = Box::new(TokenKind::Num(0, IntType::Int));
+                }
+                token
+            })
+            .collect::<Vec<_>>();
+        let Some(last) = tokens.last() else {
+            return Err(self.error_at(
+                ErrorCode::InvalidIfExpression,
+                &pound.span,
+                "#if with no expression",
+            ));
+        };
+        let mut eof = Token::new(TokenKind::Eof, last.pos.clone());
+        eof.span = last.span.shrink_to_end();
+        tokens.push(eof);
+
+        let mut tokens = TokenStream::new(tokens.into_iter());
+        let expr = Parser::new().parse_expr(&mut tokens)?;
+        if !tokens.at_eof() {
+            let token = tokens.next().unwrap();
+            return Err(self.error_at(
+                ErrorCode::InvalidIfExpression,
+                &token.span,
+                &format!("missing binary operator before token \"{}\"", token.kind),
+            ));
+        }
+
+        match Analyzer::eval_expr(&Analyzer::down_expr(expr)) {
+            Ok(value) => Ok(value != 0),
+            Err(mut err) => {
+                err.message.push_str(" in #if");
+                Err(err)
+            }
+        }
+    }
+
+    /// Replace `defined NAME`, `defined(NAME)` and `__has_include(...)` with `1` or `0`
+    /// before the line is macro-expanded.
+    fn replace_defined(
+        &self,
+        pound: &Token,
+        line: Vec<PpToken>,
+    ) -> Result<Vec<PpToken>, Diagnostic> {
+        let mut output = Vec::new();
+        let mut i = 0;
+        while i < line.len() {
+            let pp_token = &line[i];
+            let value = match &*pp_token.token.kind {
+                TokenKind::Ident(name) if name == "defined" => {
+                    let paren = matches!(
+                        line.get(i + 1).map(|pp_token| &*pp_token.token.kind),
+                        Some(TokenKind::OpenDelim(DelimToken::Paren))
+                    );
+                    let name_index = if paren { i + 2 } else { i + 1 };
+                    let name = match line.get(name_index).map(|pp_token| &*pp_token.token.kind) {
+                        Some(TokenKind::Ident(name)) => name,
+                        _ => {
+                            return Err(self.error_at(
+                                ErrorCode::InvalidIfExpression,
+                                &pp_token.token.span,
+                                "operator \"defined\" requires an identifier",
+                            ));
+                        }
+                    };
+                    i = name_index + 1;
+                    if paren {
+                        self.expect_close_paren(pp_token, line.get(i))?;
+                        i += 1;
+                    }
+                    self.is_defined(name)
+                }
+                TokenKind::Ident(name) if name == "__has_include" => {
+                    match line.get(i + 1).map(|pp_token| &*pp_token.token.kind) {
+                        Some(TokenKind::OpenDelim(DelimToken::Paren)) => {}
+                        _ => {
+                            return Err(self.error_at(
+                                ErrorCode::InvalidIfExpression,
+                                &pp_token.token.span,
+                                "missing '(' after __has_include",
+                            ));
+                        }
+                    }
+                    let (name, quoted, len) = self.header_name(pound, &line[i + 2..])?;
+                    i += 2 + len;
+                    self.expect_close_paren(pp_token, line.get(i))?;
+                    i += 1;
+                    self.search_include(&name, quoted).is_some()
+                }
+                _ => {
+                    output.push(pp_token.clone());
+                    i += 1;
+                    continue;
+                }
+            };
+
+            let mut token = pp_token.token.clone();
+            token.kind = Box::new(TokenKind::Num(value as isize, IntType::Int));
+            output.push(PpToken::new(token));
+        }
+        Ok(output)
+    }
+
+    fn expect_close_paren(
+        &self,
+        operator: &PpToken,
+        token: Option<&PpToken>,
+    ) -> Result<(), Diagnostic> {
+        match token.map(|pp_token| &*pp_token.token.kind) {
+            Some(TokenKind::CloseDelim(DelimToken::Paren)) => Ok(()),
+            _ => Err(self.error_at(
+                ErrorCode::InvalidIfExpression,
+                &operator.token.span,
+                &format!("missing ')' after \"{}\"", operator.token.kind),
+            )),
+        }
+    }
+
+    /// Pop the tokens up to the end of the current line.
+    fn take_line(pending: &mut VecDeque<PpToken>) -> Vec<PpToken> {
+        let mut line = Vec::new();
+        while let Some(pp_token) = pending.front() {
+            if pp_token.token.at_bol || *pp_token.token.kind == TokenKind::Eof {
+                break;
+            }
+            line.extend(pending.pop_front());
+        }
+        line
+    }
+
+    fn expect_macro_name(&self, pound: &Token, line: &[PpToken]) -> Result<String, Diagnostic> {
+        match line.first() {
+            Some(PpToken { token, .. }) => match &*token.kind {
+                TokenKind::Ident(name) => Ok(name.clone()),
+                _ => Err(self.error_at(
+                    ErrorCode::InvalidMacroName,
+                    &token.span,
+                    "macro names must be identifiers",
+                )),
+            },
+            None => Err(self.error_at(
+                ErrorCode::InvalidMacroName,
+                &pound.span,
+                "no macro name given in directive",
+            )),
+        }
+    }
+
+    fn define(&mut self, pound: &Token, line: &[PpToken]) -> Result<(), Diagnostic> {
+        let name = self.expect_macro_name(pound, line)?;
+        let mut rest = line[1..].iter().map(|pp_token| &pp_token.token).peekable();
+
+        // A function-like macro has its `(` directly after the name
+        let mut is_variadic = false;
+        let params = match rest.peek() {
+            Some(paren)
+                if *paren.kind == TokenKind::OpenDelim(DelimToken::Paren) && !paren.has_space =>
+            {
+                rest.next();
+                let mut params = Vec::new();
+                if rest.peek().map(|token| &*token.kind)
+                    == Some(&TokenKind::CloseDelim(DelimToken::Paren))
+                {
+                    rest.next();
+                } else {
+                    loop {
+                        match rest.next() {
+                            Some(Token { kind, span, .. }) => match &**kind {
+                                TokenKind::Ident(param) => params.push(param.clone()),
+                                TokenKind::DotDotDot => {
+                                    params.push(VA_ARGS.to_string());
+                                    is_variadic = true;
+                                }
+                                _ => {
+                                    return Err(self.error_at(
+                                        ErrorCode::InvalidMacroParameters,
+                                        span,
+                                        "expected parameter name",
+                                    ));
+                                }
+                            },
+                            None => {
+                                return Err(self.error_at(
+                                    ErrorCode::InvalidMacroParameters,
+                                    &pound.span,
+                                    "missing ')' in macro parameter list",
+                                ));
+                            }
+                        }
+                        match rest.next().map(|token| (&*token.kind, &token.span)) {
+                            Some((TokenKind::Comma, pos)) if is_variadic => {
+                                return Err(self.error_at(
+                                    ErrorCode::InvalidMacroParameters,
+                                    pos,
+                                    "expected ')' after \"...\"",
+                                ));
+                            }
+                            Some((TokenKind::Comma, _)) => continue,
+                            Some((TokenKind::CloseDelim(DelimToken::Paren), _)) => break,
+                            Some((_, pos)) => {
+                                return Err(self.error_at(
+                                    ErrorCode::InvalidMacroParameters,
+                                    pos,
+                                    "expected ',' or ')'",
+                                ));
+                            }
+                            None => {
+                                return Err(self.error_at(
+                                    ErrorCode::InvalidMacroParameters,
+                                    &pound.span,
+                                    "missing ')' in macro parameter list",
+                                ));
+                            }
+                        }
+                    }
+                }
+                Some(params)
+            }
+            _ => None,
+        };
+
+        let body: Vec<Token> = rest.cloned().collect();
+
+        for (i, token) in body.iter().enumerate() {
+            match &*token.kind {
+                TokenKind::PoundPound if i == 0 || i == body.len() - 1 => {
+                    return Err(self.error_at(
+                        ErrorCode::InvalidMacroBody,
+                        &token.span,
+                        "'##' cannot appear at either end of a macro expansion",
+                    ));
+                }
+                TokenKind::Pound if params.is_some() => {
+                    let is_param = match body.get(i + 1).map(|token| &*token.kind) {
+                        Some(TokenKind::Ident(name)) => params.iter().flatten().any(|p| p == name),
+                        _ => false,
+                    };
+                    if !is_param {
+                        return Err(self.error_at(
+                            ErrorCode::InvalidMacroBody,
+                            &token.span,
+                            "'#' is not followed by a macro parameter",
+                        ));
+                    }
+                }
+                // the content of `__VA_OPT__` is checked like a replacement list of its own
+                TokenKind::Ident(va_opt) if is_variadic && va_opt == "__VA_OPT__" => {
+                    let len = self.va_opt_len(token, &body[i + 1..])?;
+                    let content = &body[i + 2..i + len];
+                    let paste_at_end = [content.first(), content.last()]
+                        .into_iter()
+                        .flatten()
+                        .find(|token| *token.kind == TokenKind::PoundPound);
+                    if let Some(token) = paste_at_end {
+                        return Err(self.error_at(
+                            ErrorCode::InvalidMacroBody,
+                            &token.span,
+                            "'##' cannot appear at either end of __VA_OPT__",
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.macros.insert(
+            name,
+            Macro {
+                params,
+                is_variadic,
+                body,
+                span: line[0].token.span.clone(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Run `#include`, or with `next`, `#include_next`, which searches the directories
+    /// after the one the current file was found in.
+    fn include(
+        &mut self,
+        pound: &Token,
+        line: Vec<PpToken>,
+        next: bool,
+        output: &mut Vec<Token>,
+    ) -> Result<(), Diagnostic> {
+        // `#include MACRO` is allowed if MACRO expands to one of the two forms
+        let line = match line.first().map(|pp_token| &*pp_token.token.kind) {
+            Some(TokenKind::Str(_) | TokenKind::Lt) => line,
+            _ => self.expand_all(line)?,
+        };
+
+        let (name, quoted, _) = self.header_name(pound, &line)?;
+
+        if self.files.len() >= MAX_INCLUDE_DEPTH {
+            return Err(self
+                .error_at(
+                    ErrorCode::IncludeTooDeep,
+                    &pound.span,
+                    "#include nested too deeply",
+                )
+                .with_note(format!("the limit is {} levels", MAX_INCLUDE_DEPTH)));
+        }
+
+        let found = if next {
+            let start = self
+                .files
+                .last()
+                .and_then(|file| file.search_index)
+                .map_or(0, |index| index + 1);
+            self.search_dirs_from(&name, start)
+        } else {
+            self.search_include(&name, quoted)
+        };
+        let Some((path, search_index)) = found else {
+            return Err(self.error_at(
+                ErrorCode::FileNotFound,
+                &pound.span,
+                &format!("'{}' file not found", name),
+            ));
+        };
+        let key = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+
+        if self.pragma_once.contains(&key) {
+            self.stats.once_skips += 1;
+            return Ok(());
+        }
+        if self
+            .include_guards
+            .get(&key)
+            .is_some_and(|guard| self.macros.contains_key(guard))
+        {
+            self.stats.guard_skips += 1;
+            return Ok(());
+        }
+
+        let tokens = match self.token_cache.get(&key) {
+            Some(tokens) => {
+                self.stats.cache_hits += 1;
+                tokens.clone()
+            }
+            None => {
+                let input = match builtin_header(&path) {
+                    Some(input) => Ok(input.to_string()),
+                    None => fs::read_to_string(&path),
+                };
+                let input = match input {
+                    Ok(input) => input,
+                    Err(err) => {
+                        return Err(self.error_at(
+                            ErrorCode::Io,
+                            &pound.span,
+                            &format!("cannot read '{}': {}", path.display(), err),
+                        ));
+                    }
+                };
+                self.stats.files_read += 1;
+
+                let file_id = self.source_map.add(&path, input);
+                self.source_map.set_included_at(file_id, pound.span.clone());
+                let tokens =
+                    Lexer::with_file_id(&self.source_map.get(file_id).input, file_id).tokenize()?;
+                if let Some(guard) = detect_include_guard(&tokens) {
+                    self.include_guards.insert(key.clone(), guard);
+                }
+                self.token_cache.insert(key, tokens.clone());
+                tokens
+            }
+        };
+
+        let file_id = tokens.last().unwrap().pos.file_id;
+        let mut tokens = self.preprocess_tokens(file_id, search_index, tokens)?;
+        // drop the included file's Eof, or keep it at the `#include`
+        let mut eof = tokens.pop().unwrap();
+        output.extend(tokens);
+        if self.keep_file_ends {
+            eof.pos = pound.pos.clone();
+            eof.span = pound.span.clone();
+            output.push(eof);
+        }
+        Ok(())
+    }
+
+    /// Read `"name"` or `<name>` from the head of `tokens`. Returns the name, whether it
+    /// was quoted, and the number of tokens it took.
+    fn header_name(
+        &self,
+        pound: &Token,
+        tokens: &[PpToken],
+    ) -> Result<(String, bool, usize), Diagnostic> {
+        match tokens.first().map(|pp_token| &*pp_token.token.kind) {
+            Some(TokenKind::Str(name)) => Ok((name.clone(), true, 1)),
+            Some(TokenKind::Lt) => {
+                let mut name = String::new();
+                for (i, pp_token) in tokens.iter().enumerate().skip(1) {
+                    if *pp_token.token.kind == TokenKind::Gt {
+                        return Ok((name, false, i + 1));
+                    }
+                    if pp_token.token.has_space && !name.is_empty() {
+                        name.push(' ');
+                    }
+                    name.push_str(&pp_token.token.kind.to_string());
+                }
+                Err(self.error_at(
+                    ErrorCode::InvalidIncludeName,
+                    &pound.span,
+                    "missing terminating > character",
+                ))
+            }
+            _ => Err(self.error_at(
+                ErrorCode::InvalidIncludeName,
+                &pound.span,
+                "expected \"FILENAME\" or <FILENAME>",
+            )),
+        }
+    }
+
+    /// Find an included file. `"..."` looks next to the including file first, then
+    /// come the `search_dirs`. Returns the path and the index of the directory in
+    /// `search_dirs` it was found in.
+    fn search_include(&self, name: &str, quoted: bool) -> Option<(PathBuf, Option<usize>)> {
+        let current_dir = self
+            .files
+            .last()
+            .and_then(|file| self.source_map.get(file.file_id).path.parent());
+        let beside = quoted
+            .then_some(current_dir)
+            .flatten()
+            .map(|dir| dir.join(name))
+            .filter(|path| is_header(path));
+        match beside {
+            Some(path) => Some((path, None)),
+            None => self.search_dirs_from(name, 0),
+        }
+    }
+
+    /// Find `name` in the `search_dirs` from the one at `start` on.
+    fn search_dirs_from(&self, name: &str, start: usize) -> Option<(PathBuf, Option<usize>)> {
+        self.search_dirs()
+            .enumerate()
+            .skip(start)
+            .map(|(index, dir)| (dir.join(name), Some(index)))
+            .find(|(path, _)| is_header(path))
+    }
+
+    /// The directories `<...>` is searched in: the `-I` directories, chimocc's own
+    /// headers and the system directories.
+    fn search_dirs(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.include_paths
+            .iter()
+            .cloned()
+            .chain(std::iter::once(PathBuf::from(BUILTIN_INCLUDE_DIR)))
+            .chain(SYSTEM_INCLUDE_DIRS.iter().map(PathBuf::from))
+    }
+
+    /// Expand `pp_token` if it invokes a macro, pushing the replacement onto the front of
+    /// `pending` so that it is rescanned together with the rest of the input.
+    /// Returns false if the token is not a macro invocation.
+    fn expand_macro(
+        &self,
+        pp_token: &PpToken,
+        pending: &mut VecDeque<PpToken>,
+    ) -> Result<bool, Diagnostic> {
+        let TokenKind::Ident(name) = &*pp_token.token.kind else {
+            return Ok(false);
+        };
+        if pp_token.hideset.contains(name) {
+            return Ok(false);
+        }
+        if let Some(token) = self.dynamic_macro(name, &pp_token.token) {
+            pending.push_front(PpToken::new(token));
+            return Ok(true);
+        }
+        let Some(mac) = self.macros.get(name) else {
+            return Ok(false);
+        };
+
+        let (body, mut hideset, span) = match &mac.params {
+            None => (
+                self.substitute(mac, &mac.body, &[])?,
+                pp_token.hideset.clone(),
+                pp_token.token.span.clone(),
+            ),
+            Some(params) => {
+                // A function-like macro name not followed by `(` is left alone
+                match pending.front() {
+                    Some(next) if *next.token.kind == TokenKind::OpenDelim(DelimToken::Paren) => {}
+                    _ => return Ok(false),
+                }
+                let (args, rparen) = self.collect_args(pp_token, mac, params, pending)?;
+                let body = self.substitute(mac, &mac.body, &args)?;
+                let hideset = pp_token
+                    .hideset
+                    .intersection(&rparen.hideset)
+                    .cloned()
+                    .collect();
+                (body, hideset, pp_token.token.span.to(&rparen.token.span))
+            }
+        };
+        hideset.insert(name.clone());
+
+        for (i, mut expanded) in body.into_iter().enumerate().rev() {
+            expanded.hideset.extend(hideset.iter().cloned());
+            expanded.token.pos = pp_token.token.pos.clone();
+            expanded.token.span = span.expanded();
+            expanded.token.at_bol = false;
+            if i == 0 {
+                expanded.token.has_space = pp_token.token.has_space;
+            }
+            pending.push_front(expanded);
+        }
+        Ok(true)
+    }
+
+    /// Expand the macros whose value depends on where they are used.
+    fn dynamic_macro(&self, name: &str, token: &Token) -> Option<Token> {
+        let kind = match name {
+            "__FILE__" => {
+                let (path, _) = self.source_map.presumed(&token.pos);
+                let path = path.display().to_string();
+                TokenKind::Str(path.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            "__LINE__" => {
+                let (_, line) = self.source_map.presumed(&token.pos);
+                TokenKind::Num(line as isize, IntType::Int)
+            }
+            "__COUNTER__" => {
+                let counter = self.counter.get();
+                self.counter.set(counter + 1);
+                TokenKind::Num(counter as isize, IntType::Int)
+            }
+            _ => return None,
+        };
+
+        let mut expanded = token.clone();
+        expanded.kind = Box::new(kind);
+        expanded.at_bol = false;
+        expanded.span = token.span.expanded();
+        Some(expanded)
+    }
+
+    /// Read the parenthesized arguments of a function-like macro invocation.
+    /// Returns the arguments and the closing `)`.
+    fn collect_args(
+        &self,
+        name: &PpToken,
+        mac: &Macro,
+        params: &[String],
+        pending: &mut VecDeque<PpToken>,
+    ) -> Result<(Vec<Vec<PpToken>>, PpToken), Diagnostic> {
+        // skip `(`
+        pending.pop_front();
+
+        let mut args = vec![Vec::new()];
+        let mut depth = 0;
+        let rparen = loop {
+            let Some(pp_token) = pending.pop_front() else {
+                return Err(self.error_at(
+                    ErrorCode::MacroArguments,
+                    &name.token.span,
+                    "unterminated argument list invoking macro",
+                ));
+            };
+            match &*pp_token.token.kind {
+                TokenKind::Eof => {
+                    return Err(self.error_at(
+                        ErrorCode::MacroArguments,
+                        &name.token.span,
+                        "unterminated argument list invoking macro",
+                    ));
+                }
+                TokenKind::CloseDelim(DelimToken::Paren) if depth == 0 => break pp_token,
+                // the variable arguments keep their commas
+                TokenKind::Comma
+                    if depth == 0 && !(mac.is_variadic && args.len() == params.len()) =>
+                {
+                    args.push(Vec::new());
+                    continue;
+                }
+                TokenKind::OpenDelim(DelimToken::Paren) => depth += 1,
+                TokenKind::CloseDelim(DelimToken::Paren) => depth -= 1,
+                _ => {}
+            }
+            args.last_mut().unwrap().push(pp_token);
+        };
+
+        // `F()` passes no arguments to a macro without parameters
+        if params.is_empty() && args.len() == 1 && args[0].is_empty() {
+            args.clear();
+        }
+        // the variable arguments may be left out entirely
+        if mac.is_variadic && args.len() == params.len() - 1 {
+            args.push(Vec::new());
+        }
+        if args.len() != params.len() {
+            return Err(self
+                .error_at(
+                    ErrorCode::MacroArguments,
+                    &name.token.span.to(&rparen.token.span),
+                    &format!(
+                        "macro {} requires {} arguments, but {} given",
+                        name.token.kind,
+                        params.len(),
+                        args.len()
+                    ),
+                )
+                .with_label(mac.span.clone(), "macro defined here"));
+        }
+        Ok((args, rparen))
+    }
+
+    /// Build the replacement list of a macro invocation: apply `#` and `##`, and replace
+    /// the other parameters with their fully expanded arguments.
+    fn substitute(
+        &self,
+        mac: &Macro,
+        body: &[Token],
+        args: &[Vec<PpToken>],
+    ) -> Result<Vec<PpToken>, Diagnostic> {
+        let replacements = self.replace_params(mac, body, args)?;
+        Ok(replacements
+            .into_iter()
+            .filter_map(|replacement| match replacement {
+                Replacement::Token(pp_token) => Some(pp_token),
+                Replacement::Placemarker => None,
+            })
+            .collect())
+    }
+
+    fn replace_params(
+        &self,
+        mac: &Macro,
+        body: &[Token],
+        args: &[Vec<PpToken>],
+    ) -> Result<Vec<Replacement>, Diagnostic> {
+        let arg_of = |token: &Token| match &*token.kind {
+            TokenKind::Ident(name) => mac
+                .params
+                .as_ref()?
+                .iter()
+                .position(|param| param == name)
+                .map(|i| &args[i]),
+            _ => None,
+        };
+        let is_va_args = |token: &Token| matches!(&*token.kind, TokenKind::Ident(name) if mac.is_variadic && name == VA_ARGS);
+        let is_va_opt = |token: &Token| matches!(&*token.kind, TokenKind::Ident(name) if mac.is_variadic && name == "__VA_OPT__");
+
+        let mut result = Vec::new();
+        let mut i = 0;
+        while let Some(token) = body.get(i) {
+            match &*token.kind {
+                // `#param`
+                TokenKind::Pound if mac.params.is_some() => {
+                    let arg = arg_of(&body[i + 1]).unwrap();
+                    result.push(Replacement::Token(Self::stringize(token, arg)));
+                    i += 2;
+                }
+                // `lhs ## rhs`
+                TokenKind::PoundPound => {
+                    let rhs = &body[i + 1];
+                    i += 2;
+                    let lhs = result.pop().unwrap();
+
+                    // `__VA_OPT__(tokens)` is an operand like a parameter (C23 6.10.5.1)
+                    if is_va_opt(rhs) {
+                        let (replacement, len) = self.va_opt(mac, rhs, &body[i..], args)?;
+                        i += len;
+                        let mut replacement = replacement.into_iter();
+                        let first = replacement.next().unwrap_or(Replacement::Placemarker);
+                        result.push(self.paste(lhs, first)?);
+                        result.extend(replacement);
+                        continue;
+                    }
+
+                    let Some(arg) = arg_of(rhs) else {
+                        let rhs = Replacement::Token(PpToken::new(rhs.clone()));
+                        result.push(self.paste(lhs, rhs)?);
+                        continue;
+                    };
+
+                    // GNU extension: `, ## __VA_ARGS__` drops the comma when there are no
+                    // variable arguments, and pastes nothing otherwise
+                    let after_comma = matches!(
+                        &lhs,
+                        Replacement::Token(pp_token) if *pp_token.token.kind == TokenKind::Comma
+                    );
+                    if after_comma && is_va_args(rhs) {
+                        if !arg.is_empty() {
+                            result.push(lhs);
+                            result.extend(arg.iter().cloned().map(Replacement::Token));
+                        }
+                        continue;
+                    }
+
+                    let mut arg = arg.iter().cloned().map(Replacement::Token);
+                    let first = arg.next().unwrap_or(Replacement::Placemarker);
+                    result.push(self.paste(lhs, first)?);
+                    result.extend(arg);
+                }
+                TokenKind::Ident(_) if is_va_opt(token) => {
+                    let (replacement, len) = self.va_opt(mac, token, &body[i + 1..], args)?;
+                    i += 1 + len;
+                    result.extend(replacement);
+                }
+                _ => {
+                    match arg_of(token) {
+                        Some(arg) => {
+                            // an operand of `##` is not macro-expanded
+                            let pasted = matches!(
+                                body.get(i + 1).map(|token| &*token.kind),
+                                Some(TokenKind::PoundPound)
+                            );
+                            let mut arg = if pasted {
+                                arg.clone()
+                            } else {
+                                self.expand_all(arg.clone())?
+                            };
+                            match arg.first_mut() {
+                                Some(first) => first.token.has_space = token.has_space,
+                                None => result.push(Replacement::Placemarker),
+                            }
+                            result.extend(arg.into_iter().map(Replacement::Token));
+                        }
+                        None => result.push(Replacement::Token(PpToken::new(token.clone()))),
+                    }
+                    i += 1;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// `__VA_OPT__(tokens)`, followed by `rest`: the replacement of `tokens` if there are
+    /// variable arguments, and a placemarker otherwise. Also returns the number of tokens
+    /// of `rest` it takes.
+    fn va_opt(
+        &self,
+        mac: &Macro,
+        va_opt: &Token,
+        rest: &[Token],
+        args: &[Vec<PpToken>],
+    ) -> Result<(Vec<Replacement>, usize), Diagnostic> {
+        let len = self.va_opt_len(va_opt, rest)?;
+        let va_args = self.expand_all(args.last().unwrap().clone())?;
+        let replacement = if va_args.is_empty() {
+            vec![Replacement::Placemarker]
+        } else {
+            self.replace_params(mac, &rest[1..len - 1], args)?
+        };
+        Ok((replacement, len))
+    }
+
+    /// The number of tokens in the parenthesized operand of `__VA_OPT__`, parentheses
+    /// included.
+    fn va_opt_len(&self, va_opt: &Token, tokens: &[Token]) -> Result<usize, Diagnostic> {
+        if tokens.first().map(|token| &*token.kind)
+            != Some(&TokenKind::OpenDelim(DelimToken::Paren))
+        {
+            return Err(self.error_at(
+                ErrorCode::InvalidVaOpt,
+                &va_opt.span,
+                "missing '(' after __VA_OPT__",
+            ));
+        }
+        let mut depth = 0;
+        for (i, token) in tokens.iter().enumerate() {
+            match &*token.kind {
+                TokenKind::OpenDelim(DelimToken::Paren) => depth += 1,
+                TokenKind::CloseDelim(DelimToken::Paren) => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(i + 1);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(self.error_at(
+            ErrorCode::InvalidVaOpt,
+            &va_opt.span,
+            "unterminated __VA_OPT__",
+        ))
+    }
+
+    /// The rest of a directive line as written, for `#error` and `#warning`.
+    fn spell(line: &[PpToken]) -> String {
+        let mut spelling = String::new();
+        for (i, pp_token) in line.iter().enumerate() {
+            if i > 0 && pp_token.token.has_space {
+                spelling.push(' ');
+            }
+            spelling.push_str(&pp_token.token.spelling());
+        }
+        spelling
+    }
+
+    /// `#arg`: spell the unexpanded argument as a string literal.
+    fn stringize(pound: &Token, arg: &[PpToken]) -> PpToken {
+        let mut content = String::new();
+        for (i, pp_token) in arg.iter().enumerate() {
+            if i > 0 && pp_token.token.has_space {
+                content.push(' ');
+            }
+            let spelling = pp_token.token.spelling();
+            // string literals and character constants, with or without a prefix
+            let unprefixed = spelling.trim_start_matches(['L', 'u', 'U', '8']);
+            if unprefixed.starts_with(['"', '\'']) {
+                for c in spelling.chars() {
+                    if c == '"' || c == '\\' {
+                        content.push('\\');
+                    }
+                    content.push(c);
+                }
+            } else {
+                content.push_str(&spelling);
+            }
+        }
+
+        let mut token = Token::new(TokenKind::Str(content), pound.pos.clone());
+        token.span = pound.span.clone();
+        token.has_space = pound.has_space;
+        PpToken::new(token)
+    }
+
+    /// `lhs ## rhs`: concatenate the spellings, which must form a single token.
+    fn paste(&self, lhs: Replacement, rhs: Replacement) -> Result<Replacement, Diagnostic> {
+        let (lhs, rhs) = match (lhs, rhs) {
+            (Replacement::Placemarker, rhs) => return Ok(rhs),
+            (lhs, Replacement::Placemarker) => return Ok(lhs),
+            (Replacement::Token(lhs), Replacement::Token(rhs)) => (lhs.token, rhs.token),
+        };
+
+        let spelling = format!("{}{}", lhs.spelling(), rhs.spelling());
+        let mut tokens = Lexer::new(&spelling).tokenize().unwrap_or_default();
+        if tokens.len() != 2 {
+            return Err(self.error_at(
+                ErrorCode::InvalidPaste,
+                &lhs.span,
+                &format!(
+                    "pasting \"{}\" and \"{}\" does not give a valid preprocessing token",
+                    lhs.spelling(),
+                    rhs.spelling()
+                ),
+            ));
+        }
+
+        let mut token = tokens.swap_remove(0);
+        token.pos = lhs.pos;
+        token.span = lhs.span;
+        token.at_bol = false;
+        token.has_space = lhs.has_space;
+        Ok(Replacement::Token(PpToken::new(token)))
+    }
+
+    /// Macro-expand a token sequence on its own, without looking at the tokens after it.
+    fn expand_all(&self, tokens: Vec<PpToken>) -> Result<Vec<PpToken>, Diagnostic> {
+        let mut pending: VecDeque<PpToken> = tokens.into();
+        let mut output = Vec::new();
+        while let Some(pp_token) = pending.pop_front() {
+            if !self.expand_macro(&pp_token, &mut pending)? {
+                output.push(pp_token);
+            }
+        }
+        Ok(output)
+    }
+
+    fn error_at(&self, code: ErrorCode, span: &Span, msg: &str) -> Diagnostic {
+        Diagnostic::error(code, span.clone(), msg)
+    }
+}
+
+/// Whether `path` is one of chimocc's own headers or a file that exists.
+fn is_header(path: &Path) -> bool {
+    builtin_header(path).is_some() || path.is_file()
+}
+
+/// The contents of one of chimocc's own headers, if `path` names one.
+fn builtin_header(path: &Path) -> Option<&'static str> {
+    let name = path.strip_prefix(BUILTIN_INCLUDE_DIR).ok()?;
+    BUILTIN_HEADERS
+        .iter()
+        .find(|(builtin, _)| name == Path::new(builtin))
+        .map(|(_, input)| *input)
+}
+
+/// Undo the escapes of a string literal's content, as written by `__FILE__` and `-E`.
+fn unescape(content: &str) -> String {
+    let mut unescaped = String::with_capacity(content.len());
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Write preprocessed tokens back out as C source that lexes to the same tokens, for
+/// `-E`. The output starts with a `# 1 "file"` marker for the main file, and every token
+/// starts on the line it came from, with a `# line "file"` marker whenever that cannot
+/// be done by just adding newlines. As in gcc, a marker has the flag 1 when it enters an
+/// included file and 2 when it returns to the file that included it.
+pub fn write_preprocessed<W: Write>(
+    writer: &mut W,
+    tokens: &[Token],
+    source_map: &SourceMap,
+) -> io::Result<()> {
+    // the tokens end with the `Eof` of the main file
+    let Some(eof) = tokens.last() else {
+        return Ok(());
+    };
+    let main = &source_map.get(eof.pos.file_id).path;
+    writeln!(writer, "# 1 \"{}\"", escape_path(main))?;
+
+    // the line the writer is at, as a line of a file read and as reported after `#line`
+    let mut physical: Option<(usize, usize)> = None;
+    let mut presumed: Option<(&Path, usize)> = Some((main, 1));
+    // the files being included, from the main file down to the one the writer is in
+    let mut includes = vec![eof.pos.file_id];
+    // a `#pragma` has to be followed by a newline
+    let mut line_ended = false;
+    let mut prev: Option<&Token> = None;
+
+    for (i, token) in tokens.iter().enumerate() {
+        if *token.kind == TokenKind::Eof {
+            let file_id = token.pos.file_id;
+            // the last one ends the main file, and a file with no tokens was not entered
+            if i + 1 == tokens.len() || includes.last() == Some(&file_id) {
+                continue;
+            }
+            // the end of an included file, kept at the `#include` by `set_keep_file_ends`:
+            // return to the includer, or if it was not entered either, go on in it in
+            // place of the file that ended
+            includes.pop();
+            let flag = if includes.last() == Some(&file_id) {
+                " 2"
+            } else {
+                includes.push(file_id);
+                ""
+            };
+            let (path, line) = source_map.presumed(&token.pos);
+            if prev.is_some() {
+                writeln!(writer)?;
+            }
+            write!(writer, "# {} \"{}\"{}", line + 1, escape_path(path), flag)?;
+            // the marker stands for the line of the `#include`
+            physical = Some((file_id, token.pos.n_line));
+            presumed = Some((path, line));
+            line_ended = false;
+            prev = Some(token);
+            continue;
+        }
+        let is_pragma = matches!(*token.kind, TokenKind::Pragma(_));
+        let Position {
+            file_id, n_line, ..
+        } = token.pos;
+
+        let on_current_line = !line_ended
+            && !is_pragma
+            && physical
+                .is_some_and(|(cur_file, cur_line)| cur_file == file_id && n_line <= cur_line);
+        if on_current_line {
+            if token.has_space || prev.is_some_and(|prev| needs_space(prev, token, source_map)) {
+                write!(writer, " ")?;
+            }
+        } else {
+            let flag = match includes.iter().position(|&id| id == file_id) {
+                Some(depth) if depth + 1 < includes.len() => {
+                    includes.truncate(depth + 1);
+                    " 2"
+                }
+                Some(_) => "",
+                None => {
+                    includes.push(file_id);
+                    " 1"
+                }
+            };
+            let (path, line) = source_map.presumed(&token.pos);
+            match presumed {
+                // the first line may be the one of the leading marker
+                Some((cur_path, cur_line))
+                    if flag.is_empty()
+                        && cur_path == path
+                        && (line > cur_line || prev.is_none() && line == cur_line)
+                        && line - cur_line <= 8 =>
+                {
+                    write!(writer, "{}", "\n".repeat(line - cur_line))?;
+                }
+                _ => {
+                    if prev.is_some() {
+                        writeln!(writer)?;
+                    }
+                    writeln!(writer, "# {} \"{}\"{}", line, escape_path(path), flag)?;
+                }
+            }
+            write!(writer, "{}", " ".repeat(token.pos.n_char))?;
+            physical = Some((file_id, n_line));
+            presumed = Some((path, line));
+        }
+
+        write!(writer, "{}", token.spelling())?;
+        line_ended = is_pragma;
+        prev = Some(token);
+    }
+
+    if prev.is_some() {
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// `path` as written in a line marker, in a string literal.
+fn escape_path(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+}
+
+/// Whether `prev` immediately followed by `next` would lex differently, e.g. `-` `-`
+/// from different macros, or `/` `*` which would start a comment.
+fn needs_space(prev: &Token, next: &Token, source_map: &SourceMap) -> bool {
+    const PUNCTUATORS: [&str; 28] = [
+        "->", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=", "%=", "+=",
+        "-=", "&=", "^=", "|=", "##", "<:", ":>", "<%", "%>", "%:", "..", "//", "/*",
+    ];
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let (prev_spelling, next_spelling) = (prev.spelling(), next.spelling());
+    let (Some(last), Some(first)) = (prev_spelling.chars().last(), next_spelling.chars().next())
+    else {
+        return false;
+    };
+
+    // written like that in the source, so they lex as these two tokens again
+    let joined = format!("{}{}", prev_spelling, next_spelling);
+    let in_source = prev.pos.file_id == next.pos.file_id
+        && prev.pos.n_line == next.pos.n_line
+        && prev.pos.n_char + prev_spelling.chars().count() == next.pos.n_char
+        && source_map
+            .line(prev.pos.file_id, prev.pos.n_line)
+            .is_some_and(|line| match line.char_indices().nth(prev.pos.n_char) {
+                Some((start, _)) => line[start..].starts_with(&joined),
+                None => false,
+            });
+    if in_source {
+        return false;
+    }
+
+    // an identifier followed by a string could also become an encoding prefix like `u8"`
+    (is_word(last) && (is_word(first) || first == '"' || first == '\''))
+        || PUNCTUATORS.contains(&format!("{}{}", last, first).as_str())
+}
+
+/// Find the guard macro of a file of the form `#ifndef X / #define X / ... / #endif`
+/// with nothing outside the `#ifndef` group.
+fn detect_include_guard(tokens: &[Token]) -> Option<String> {
+    let is_directive = |i: usize, name: &str| {
+        matches!(tokens.get(i), Some(token) if *token.kind == TokenKind::Pound && token.at_bol)
+            && matches!(tokens.get(i + 1), Some(token) if *token.kind == TokenKind::Ident(name.to_string()))
+    };
+    let ident = |i: usize| match tokens.get(i).map(|token| &*token.kind) {
+        Some(TokenKind::Ident(name)) => Some(name.clone()),
+        _ => None,
+    };
+
+    if !is_directive(0, "ifndef") || !is_directive(3, "define") {
+        return None;
+    }
+    let guard = ident(2)?;
+    if ident(5).as_ref() != Some(&guard) {
+        return None;
+    }
+
+    let mut depth = 0;
+    for i in 6..tokens.len() {
+        if is_directive(i, "if") || is_directive(i, "ifdef") || is_directive(i, "ifndef") {
+            depth += 1;
+        } else if depth == 0 && (is_directive(i, "elif") || is_directive(i, "else")) {
+            return None;
+        } else if is_directive(i, "endif") {
+            if depth > 0 {
+                depth -= 1;
+                continue;
+            }
+            // the `#endif` of the guard must end the file
+            let rest = &tokens[i + 2..];
+            let end = rest
+                .iter()
+                .position(|token| token.at_bol || *token.kind == TokenKind::Eof)?;
+            return (*rest[end].kind == TokenKind::Eof).then_some(guard);
+        }
+    }
+    None
+}
+
+/// `__DATE__` and `__TIME__` for now in UTC, or for `SOURCE_DATE_EPOCH` if it is set so
+/// that builds can be reproduced.
+fn date_time() -> (String, String) {
+    let secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<i64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs() as i64)
+        });
+    date_time_at(secs)
+}
+
+/// `__DATE__` (`Mmm dd yyyy`) and `__TIME__` (`hh:mm:ss`) for seconds since the Unix epoch.
+fn date_time_at(secs: i64) -> (String, String) {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // days since 1970-01-01 to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    (
+        format!("{} {:>2} {}", MONTHS[month as usize - 1], day, year),
+        format!(
+            "{:02}:{:02}:{:02}",
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preprocess(input: &str) -> Vec<TokenKind> {
+        Preprocessor::with_source_map(Vec::new(), SourceMap::new())
+            .preprocess(Path::new("test.c"), input.to_string())
+            .unwrap()
+            .into_iter()
+            .map(|token| *token.kind)
+            .collect()
+    }
+
+    fn error(input: &str) -> Diagnostic {
+        Preprocessor::with_source_map(Vec::new(), SourceMap::new())
+            .preprocess(Path::new("test.c"), input.to_string())
+            .unwrap_err()
+    }
+
+    fn expected(input: &str) -> Vec<TokenKind> {
+        Lexer::new(input)
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|token| *token.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_object_like_macro() {
+        assert_eq!(
+            preprocess("#define ONE 1\n#define TWO ONE + ONE\nTWO * 3"),
+            expected("1 + 1 * 3")
+        );
+        assert_eq!(preprocess("#define X 1\nX\n#undef X\nX"), expected("1 X"));
+        assert_eq!(preprocess("#\n1"), expected("1"));
+    }
+
+    #[test]
+    fn test_function_like_macro() {
+        assert_eq!(
+            preprocess("#define ADD(a, b) ((a) + (b))\nADD(1, (2, 3))"),
+            expected("((1) + ((2, 3)))")
+        );
+        assert_eq!(
+            preprocess("#define F() 1\n#define G (x) x\nF() + G"),
+            expected("1 + (x) x")
+        );
+        // not followed by `(`, so not an invocation
+        assert_eq!(preprocess("#define F(x) x\nF + 1"), expected("F + 1"));
+        // arguments are expanded before substitution
+        assert_eq!(
+            preprocess("#define ONE 1\n#define ID(x) x\nID(ID(ONE))"),
+            expected("1")
+        );
+        // the invocation may span lines
+        assert_eq!(
+            preprocess("#define ADD(a, b) a + b\nADD(1,\n2)"),
+            expected("1 + 2")
+        );
+    }
+
+    #[test]
+    fn test_recursive_macro() {
+        assert_eq!(preprocess("#define x x + 1\nx"), expected("x + 1"));
+        assert_eq!(preprocess("#define a b\n#define b a\na b"), expected("a b"));
+        assert_eq!(
+            preprocess("#define f(x) x + f(x)\nf(f(1))"),
+            expected("1 + f(1) + f(1 + f(1))")
+        );
+        // `g` is rescanned together with the tokens after the expansion
+        assert_eq!(
+            preprocess("#define f g\n#define g(x) x * 2\nf(3)"),
+            expected("3 * 2")
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("chimocc_pp_{}", std::process::id()));
+        fs::create_dir_all(dir.join("sys")).unwrap();
+        fs::write(dir.join("quoted.h"), "#define QUOTED 1\n").unwrap();
+        fs::write(dir.join("sys").join("angled.h"), "2 +\n").unwrap();
+
+        let input = "#include \"quoted.h\"\n#include <sys/angled.h>\nQUOTED";
+        let kinds = Preprocessor::with_source_map(vec![dir.clone()], SourceMap::new())
+            .preprocess(&dir.join("main.c"), input.to_string())
+            .unwrap()
+            .into_iter()
+            .map(|token| *token.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, expected("2 + 1"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_next() {
+        let dir = std::env::temp_dir().join(format!("chimocc_next_{}", std::process::id()));
+        for sub in ["a", "b", "c"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        fs::write(dir.join("a").join("x.h"), "1 +\n#include_next <x.h>\n").unwrap();
+        fs::write(dir.join("b").join("x.h"), "2 +\n#include_next <x.h>\n").unwrap();
+        fs::write(dir.join("c").join("x.h"), "3\n").unwrap();
+
+        let include_paths = vec![dir.join("a"), dir.join("b"), dir.join("c")];
+        let kinds = Preprocessor::with_source_map(include_paths, SourceMap::new())
+            .preprocess(&dir.join("main.c"), "#include <x.h>".to_string())
+            .unwrap()
+            .into_iter()
+            .map(|token| *token.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, expected("1 + 2 + 3"));
+
+        // from a file not found in the search path, the search starts from the beginning
+        let kinds = Preprocessor::with_source_map(vec![dir.join("c")], SourceMap::new())
+            .preprocess(&dir.join("main.c"), "#include_next <x.h>".to_string())
+            .unwrap()
+            .into_iter()
+            .map(|token| *token.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, expected("3"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stringize() {
+        assert_eq!(
+            preprocess("#define S(x) #x\nS(  a  +  b ) S() S(\"q\\\\\") S(S(1))"),
+            vec![
+                TokenKind::Str("a + b".to_string()),
+                TokenKind::Str("".to_string()),
+                TokenKind::Str(r#"\"q\\\\\""#.to_string()),
+                TokenKind::Str("S(1)".to_string()),
+                TokenKind::Eof
+            ]
+        );
+        // constants keep their spelling, and quotes in character constants are escaped
+        assert_eq!(
+            preprocess("#define S(x) #x\nS(0x10 010 1u 'a' L'\"')"),
+            vec![
+                TokenKind::Str(r#"0x10 010 1u 'a' L'\"'"#.to_string()),
+                TokenKind::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_paste() {
+        assert_eq!(
+            preprocess("#define CAT(a, b) a ## b\nCAT(1, 2) CAT(x, 1) CAT(, y) CAT(z, ) CAT(,)"),
+            expected("12 x1 y z")
+        );
+        assert_eq!(
+            preprocess("#define XY 3\n#define CAT(a, b) a ## b\nCAT(X, Y)"),
+            expected("3")
+        );
+        // an operand of `##` is not expanded first
+        assert_eq!(
+            preprocess("#define ONE 1\n#define CAT(a, b) a ## b\nCAT(ONE, 2)"),
+            expected("ONE2")
+        );
+        assert_eq!(
+            preprocess("#define LT < ## =\n#define HEX(n) 0x ## n\nLT HEX(1f)"),
+            expected("<= 0x1f")
+        );
+        // the spelling is pasted, not the value
+        assert_eq!(
+            preprocess("#define CAT(a, b) a ## b\nCAT(010, 7) CAT(0x1, 0)"),
+            expected("0107 0x10")
+        );
+    }
+
+    #[test]
+    fn test_paste_invalid() {
+        assert_eq!(
+            error("#define CAT(a, b) a ## b\nCAT(+, -)").message,
+            "pasting \"+\" and \"-\" does not give a valid preprocessing token"
+        );
+    }
+
+    #[test]
+    fn test_paste_at_end() {
+        assert_eq!(
+            error("#define CAT(a, b) a b ##\n").message,
+            "'##' cannot appear at either end of a macro expansion"
+        );
+    }
+
+    #[test]
+    fn test_variadic_macro() {
+        assert_eq!(
+            preprocess("#define F(a, ...) a: __VA_ARGS__\nF(1) F(1, 2) F(1, 2, (3, 4))"),
+            expected("1: 1: 2 1: 2, (3, 4)")
+        );
+        assert_eq!(
+            preprocess("#define S(...) #__VA_ARGS__\nS(a,b , c)"),
+            vec![TokenKind::Str("a,b , c".to_string()), TokenKind::Eof]
+        );
+        assert_eq!(
+            preprocess(
+                "#define LOG(fmt, ...) f(fmt __VA_OPT__(,) __VA_ARGS__)\nLOG(1) LOG(1, 2, 3)"
+            ),
+            expected("f(1) f(1, 2, 3)")
+        );
+        assert_eq!(
+            preprocess("#define LOG(fmt, ...) f(fmt, ## __VA_ARGS__)\nLOG(1) LOG(1, 2, 3)"),
+            expected("f(1) f(1, 2, 3)")
+        );
+    }
+
+    #[test]
+    fn test_va_opt_paste() {
+        assert_eq!(
+            preprocess("#define F(a, ...) a ## __VA_OPT__(b c)\nF(x) F(x, 1)"),
+            expected("x xb c")
+        );
+        assert_eq!(
+            preprocess("#define F(a, ...) __VA_OPT__(b c) ## a\nF(x) F(x, 1)"),
+            expected("x b cx")
+        );
+        assert_eq!(
+            error("#define F(...) __VA_OPT__(## x)\nF(1)").message,
+            "'##' cannot appear at either end of __VA_OPT__"
+        );
+        assert_eq!(
+            error("#define F(...) __VA_OPT__(x ##)\nF(1)").message,
+            "'##' cannot appear at either end of __VA_OPT__"
+        );
+    }
+
+    #[test]
+    fn test_conditional() {
+        assert_eq!(
+            preprocess("#if 1 + 1 == 2\n1\n#else\n2\n#endif"),
+            expected("1")
+        );
+        assert_eq!(
+            preprocess(
+                "#define V 3\n#if V < 2\n1\n#elif V < 3\n2\n#elif V < 4\n3\n#else\n4\n#endif"
+            ),
+            expected("3")
+        );
+        assert_eq!(
+            preprocess("#define A\n#ifdef A\n1\n#endif\n#ifndef A\n2\n#endif"),
+            expected("1")
+        );
+        assert_eq!(
+            preprocess("#if defined(A) || defined B || UNDEFINED\n1\n#else\n2\n#endif"),
+            expected("2")
+        );
+        assert_eq!(
+            preprocess("#define VERSION 3\n#define X\n#if defined(X) && VERSION >= 3\n1\n#endif"),
+            expected("1")
+        );
+        // nested groups inside a skipped group are skipped as a whole
+        assert_eq!(
+            preprocess("#if 0\n#if 1\n1\n#else\n2\n#endif\n3\n#else\n4\n#endif"),
+            expected("4")
+        );
+        // a taken branch stops later `#elif`s from being evaluated
+        assert_eq!(
+            preprocess("#if 1\n1\n#elif 1 / 0\n2\n#endif"),
+            expected("1")
+        );
+        assert_eq!(
+            preprocess("#if 0 ? 1 / 0 : !0 && ~0 == -1\n1\n#endif"),
+            expected("1")
+        );
+    }
+
+    #[test]
+    fn test_conditional_unsigned() {
+        // the signed types act as intmax_t and the unsigned ones as uintmax_t
+        assert_eq!(
+            preprocess("#if -1 < 0u\n1\n#else\n2\n#endif"),
+            expected("2")
+        );
+        assert_eq!(
+            preprocess("#if -1L < 1u\n1\n#else\n2\n#endif"),
+            expected("2")
+        );
+        assert_eq!(preprocess("#if (0u - 1) / 2 > 5\n1\n#endif"), expected("1"));
+        assert_eq!(preprocess("#if -1 / 2 == 0\n1\n#endif"), expected("1"));
+        assert_eq!(
+            preprocess("#if (0u - 1) >> 63 == 1\n1\n#endif"),
+            expected("1")
+        );
+        assert_eq!(preprocess("#if -1 >> 63 == -1\n1\n#endif"), expected("1"));
+    }
+
+    #[test]
+    fn test_skipped_group_is_not_lexed() {
+        assert_eq!(
+            preprocess("#if 0\n@ 09 \"unterminated\n#error no\n#endif\n1"),
+            expected("1")
+        );
+    }
+
+    #[test]
+    fn test_include_guard_and_pragma_once() {
+        let dir = std::env::temp_dir().join(format!("chimocc_guard_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("guarded.h"), "#ifndef G\n#define G\n1\n#endif\n").unwrap();
+        fs::write(dir.join("once.h"), "#pragma once\n2\n").unwrap();
+        fs::write(dir.join("plain.h"), "3\n").unwrap();
+
+        let input = "#include \"guarded.h\"\n#include \"guarded.h\"\n\
+                     #include \"once.h\"\n#include \"once.h\"\n\
+                     #include \"plain.h\"\n#include \"plain.h\"\n";
+        let mut preprocessor = Preprocessor::with_source_map(Vec::new(), SourceMap::new());
+        let kinds = preprocessor
+            .preprocess(&dir.join("main.c"), input.to_string())
+            .unwrap()
+            .into_iter()
+            .map(|token| *token.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, expected("1 2 3 3"));
+
+        let stats = preprocessor.stats();
+        assert_eq!(stats.files_read, 3);
+        assert_eq!(stats.guard_skips, 1);
+        assert_eq!(stats.once_skips, 1);
+        assert_eq!(stats.cache_hits, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_detect_include_guard() {
+        let guard = |input: &str| detect_include_guard(&Lexer::new(input).tokenize().unwrap());
+
+        assert_eq!(
+            guard("#ifndef G\n#define G\n#if 1\n#endif\n#endif\n"),
+            Some("G".to_string())
+        );
+        assert_eq!(guard("#ifndef G\n#define G\n#endif\n1"), None);
+        assert_eq!(guard("1\n#ifndef G\n#define G\n#endif\n"), None);
+        assert_eq!(guard("#ifndef G\n#define H\n#endif\n"), None);
+        assert_eq!(guard("#ifndef G\n#define G\n#else\n#endif\n"), None);
+    }
+
+    #[test]
+    fn test_has_include() {
+        let dir = std::env::temp_dir().join(format!("chimocc_has_include_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("present.h"), "").unwrap();
+
+        let input = "#if __has_include(\"present.h\") && !__has_include(<absent.h>)\n1\n#endif\n\
+                     #ifdef __has_include\n2\n#endif";
+        let kinds = Preprocessor::with_source_map(Vec::new(), SourceMap::new())
+            .preprocess(&dir.join("main.c"), input.to_string())
+            .unwrap()
+            .into_iter()
+            .map(|token| *token.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, expected("1 2"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_predefined_macro() {
+        assert_eq!(
+            preprocess("__STDC__ __STDC_VERSION__ __SIZEOF_LONG__"),
+            vec![
+                TokenKind::Num(1, IntType::Int),
+                TokenKind::Num(201112, IntType::Long),
+                TokenKind::Num(8, IntType::Int),
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            preprocess("#if defined(__x86_64__) && __LP64__ && __linux__\n1\n#endif"),
+            expected("1")
+        );
+        // the GNU extensions of glibc's headers are defined away, without claiming GNU C
+        assert_eq!(
+            preprocess(
+                "#ifndef __GNUC__\n__extension__ __attribute__((__nonnull__ (1, 2))) \
+                 __asm__ (\"\" \"f\") 1\n#endif"
+            ),
+            expected("1")
+        );
+        assert!(matches!(
+            preprocess("__DATE__ __TIME__").as_slice(),
+            [TokenKind::Str(date), TokenKind::Str(time), TokenKind::Eof]
+                if date.len() == 11 && time.len() == 8
+        ));
+    }
+
+    #[test]
+    fn test_dynamic_macro() {
+        assert_eq!(
+            preprocess("#define L __LINE__\n__LINE__\n\nL __FILE__\n__COUNTER__ __COUNTER__"),
+            vec![
+                TokenKind::Num(2, IntType::Int),
+                TokenKind::Num(4, IntType::Int),
+                TokenKind::Str("test.c".to_string()),
+                TokenKind::Num(0, IntType::Int),
+                TokenKind::Num(1, IntType::Int),
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(preprocess("#ifdef __LINE__\n1\n#endif"), expected("1"));
+    }
+
+    #[test]
+    fn test_line_directive() {
+        assert_eq!(
+            preprocess(
+                "#line 10\n__LINE__\n__LINE__ __FILE__\n#define N 20\n#line N \"a.c\"\n__LINE__ __FILE__"
+            ),
+            vec![
+                TokenKind::Num(10, IntType::Int),
+                TokenKind::Num(11, IntType::Int),
+                TokenKind::Str("test.c".to_string()),
+                TokenKind::Num(20, IntType::Int),
+                TokenKind::Str("a.c".to_string()),
+                TokenKind::Eof
+            ]
+        );
+        // a line marker as written by -E, with flags
+        assert_eq!(
+            preprocess("# 5 \"dir\\\\b.h\" 1 3\n__LINE__ __FILE__"),
+            vec![
+                TokenKind::Num(5, IntType::Int),
+                TokenKind::Str("dir\\\\b.h".to_string()),
+                TokenKind::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_line_directive_zero() {
+        assert_eq!(
+            error("#line 0\n").message,
+            "\"0\" after #line is not a positive integer"
+        );
+    }
+
+    #[test]
+    fn test_error_directive() {
+        let diagnostic = error("#if 1\n#error unsupported\n#endif\n");
+        assert_eq!(diagnostic.message, "#error unsupported");
+        assert_eq!(diagnostic.span, Some(Span::new(1, 6, 7)));
+    }
+
+    #[test]
+    fn test_warnings() {
+        let mut preprocessor = Preprocessor::with_source_map(Vec::new(), SourceMap::new());
+        let input = "#warning check this\n#line 3 \"a.c\" 1\n1";
+        let tokens = preprocessor
+            .preprocess(Path::new("test.c"), input.to_string())
+            .unwrap();
+        assert_eq!(tokens.len(), 2);
+        let messages: Vec<_> = preprocessor
+            .warnings()
+            .iter()
+            .map(|warning| warning.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            ["#warning check this", "extra tokens at end of #line"]
+        );
+    }
+
+    #[test]
+    fn test_error_labels() {
+        let diagnostic = error("#define F(x) x\nF(1, 2)");
+        assert_eq!(
+            diagnostic.message,
+            "macro F requires 1 arguments, but 2 given"
+        );
+        assert_eq!(diagnostic.span, Some(Span::new(1, 15, 22)));
+        assert_eq!(diagnostic.labels[0].span, Span::new(1, 8, 9));
+
+        let diagnostic = error("#if 0\n#else\n#else\n#endif");
+        assert_eq!(diagnostic.message, "#else after #else");
+        assert_eq!(diagnostic.labels[0].span, Span::new(1, 0, 1));
+    }
+
+    #[test]
+    fn test_directive_suggestion() {
+        let diagnostic = error("#inlcude <stddef.h>");
+        assert_eq!(
+            diagnostic.message,
+            "invalid preprocessing directive #inlcude; did you mean #include?"
+        );
+        assert_eq!(diagnostic.fixits[0].span, Span::new(1, 1, 8));
+        assert_eq!(diagnostic.fixits[0].replacement, "include");
+
+        assert!(error("#foo").fixits.is_empty());
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let diagnostic = error("#if 1 + 2 / (1 - 1)\n#endif");
+        assert_eq!(diagnostic.message, "division by zero in #if");
+        assert_eq!(diagnostic.span, Some(Span::new(1, 8, 19)));
+
+        // tokens from a macro expansion have the span of the whole invocation
+        let diagnostic = error("#define DIV(a, b) a / b\n#if DIV(1, 0)\n#endif");
+        assert_eq!(diagnostic.span, Some(Span::new(1, 28, 37).expanded()));
+    }
+
+    #[test]
+    fn test_error_and_warning_in_skipped_group() {
+        assert_eq!(
+            preprocess("#if 0\n#error unsupported\n#else\n#warning fallback\n1\n#endif\n"),
+            expected("1")
+        );
+    }
+
+    #[test]
+    fn test_pragma() {
+        let kinds = preprocess("#pragma pack(push, 4)\n1\n#pragma once\n");
+        assert_eq!(kinds.len(), 3);
+        assert_eq!(kinds[0].to_string(), "#pragma pack(push, 4)");
+        assert_eq!(kinds[1..], expected("1"));
+    }
+
+    #[test]
+    fn test_dynamic_macro_in_include() {
+        let dir = std::env::temp_dir().join(format!("chimocc_line_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("header.h"), "\n\n__LINE__ __FILE__\n").unwrap();
+
+        let input = "#include \"header.h\"\n__LINE__ __FILE__";
+        let mut preprocessor = Preprocessor::with_source_map(Vec::new(), SourceMap::new());
+        let tokens = preprocessor
+            .preprocess(&dir.join("main.c"), input.to_string())
+            .unwrap();
+        let kinds = tokens
+            .iter()
+            .map(|token| *token.kind.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Num(3, IntType::Int),
+                TokenKind::Str(dir.join("header.h").display().to_string()),
+                TokenKind::Num(2, IntType::Int),
+                TokenKind::Str(dir.join("main.c").display().to_string()),
+                TokenKind::Eof
+            ]
+        );
+        // positions refer back to the file each token came from
+        let source_map = preprocessor.source_map();
+        assert_eq!(
+            source_map.get(tokens[0].pos.file_id).path,
+            dir.join("header.h")
+        );
+        assert_eq!(
+            source_map.get(tokens[2].pos.file_id).path,
+            dir.join("main.c")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_date_time() {
+        assert_eq!(
+            date_time_at(0),
+            ("Jan  1 1970".to_string(), "00:00:00".to_string())
+        );
+        assert_eq!(
+            date_time_at(1709210096),
+            ("Feb 29 2024".to_string(), "12:34:56".to_string())
+        );
+    }
+
+    fn write_preprocessed_string(input: &str) -> String {
+        let mut preprocessor = Preprocessor::with_source_map(Vec::new(), SourceMap::new());
+        preprocessor.set_keep_file_ends(true);
+        let tokens = preprocessor
+            .preprocess(Path::new("main.c"), input.to_string())
+            .unwrap();
+        let mut output = Vec::new();
+        write_preprocessed(&mut output, &tokens, preprocessor.source_map()).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_write_preprocessed() {
+        assert_eq!(
+            write_preprocessed_string("#define F(x) x\n1 +\n  F(2)\n"),
+            "# 1 \"main.c\"\n\n1 +\n  2\n"
+        );
+        assert_eq!(
+            write_preprocessed_string("1\n\n\n\n\n\n\n\n\n\n2\n"),
+            "# 1 \"main.c\"\n1\n# 11 \"main.c\"\n2\n"
+        );
+        // tokens that would merge when written next to each other are separated
+        assert_eq!(
+            write_preprocessed_string(
+                "#define NEG -\n#define ID(x) x\nNEG-1 ID(a)ID(b) 1/ID(*2)\n"
+            ),
+            "# 1 \"main.c\"\n\n\n- -1 a b 1/ *2\n"
+        );
+    }
+
+    #[test]
+    fn test_write_preprocessed_line_directive() {
+        assert_eq!(
+            write_preprocessed_string("1\n#pragma weak f\n2\n#line 50 \"a.c\"\n3\n4\n"),
+            "# 1 \"main.c\"\n1\n#pragma weak f\n2\n# 50 \"a.c\"\n3\n4\n"
+        );
+    }
+
+    #[test]
+    fn test_write_preprocessed_include() {
+        let dir = std::env::temp_dir().join(format!("chimocc_markers_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("outer.h"), "1\n#include \"inner.h\"\n3\n").unwrap();
+        fs::write(dir.join("inner.h"), "2\n").unwrap();
+        fs::write(dir.join("a.h"), "5\n").unwrap();
+        // b.h has no tokens of its own
+        fs::write(dir.join("b.h"), "#define B\n#include \"c.h\"\n").unwrap();
+        fs::write(dir.join("c.h"), "6\n").unwrap();
+
+        let main = dir.join("main.c");
+        let mut preprocessor = Preprocessor::with_source_map(Vec::new(), SourceMap::new());
+        preprocessor.set_keep_file_ends(true);
+        let input = "#include \"outer.h\"\n4\n#include \"a.h\"\n#include \"b.h\"\n";
+        let tokens = preprocessor.preprocess(&main, input.to_string()).unwrap();
+        let mut output = Vec::new();
+        write_preprocessed(&mut output, &tokens, preprocessor.source_map()).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        let (main, outer, inner) = (main.display(), path("outer.h"), path("inner.h"));
+        let (a, b, c) = (path("a.h"), path("b.h"), path("c.h"));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "# 1 \"{main}\"\n# 1 \"{outer}\" 1\n1\n# 1 \"{inner}\" 1\n2\n\
+                 # 3 \"{outer}\" 2\n3\n# 2 \"{main}\" 2\n4\n\
+                 # 1 \"{a}\" 1\n5\n# 4 \"{main}\" 2\n\
+                 # 1 \"{c}\" 1\n6\n# 3 \"{b}\"\n# 5 \"{main}\" 2\n"
+            )
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_preprocessed_keeps_source_spacing() {
+        // `->` is two tokens to the lexer, but must not come out as `- >`, and constants
+        // are written as in the source
+        assert_eq!(
+            write_preprocessed_string("p->x 'a' 0x10 010 1u\n"),
+            "# 1 \"main.c\"\np->x 'a' 0x10 010 1u\n"
+        );
+    }
+
+    #[test]
+    fn test_builtin_headers() {
+        assert_eq!(
+            preprocess("#include <stdbool.h>\n#include <float.h>\ntrue + FLT_RADIX"),
+            expected("1 + 2")
+        );
+        assert_eq!(
+            preprocess("#if __has_include(<stddef.h>) && __has_include(<stdarg.h>)\n1\n#endif"),
+            expected("1")
+        );
+        // included again through their include guards
+        assert_eq!(
+            preprocess("#include <stdbool.h>\n#include <stdbool.h>\nfalse"),
+            expected("0")
+        );
+        // with `__need_` macros only part of the header is defined, and they are undefined
+        let input = "#define __need_NULL\n#include <stddef.h>\n\
+                     #if defined NULL && !defined offsetof && !defined __need_NULL\n1\n#endif\n\
+                     #define __need___va_list\n#include <stdarg.h>\n\
+                     #if !defined va_start && !defined __need___va_list\n2\n#endif\n\
+                     #include <stddef.h>\n#include <stdarg.h>\n\
+                     #if defined offsetof && defined va_start\n3\n#endif";
+        let numbers: Vec<_> = preprocess(input)
+            .into_iter()
+            .filter(|kind| matches!(kind, TokenKind::Num(..)))
+            .collect();
+        assert_eq!(numbers, expected("1 2 3")[..3]);
+    }
+
+    #[test]
+    fn test_write_preprocessed_roundtrip() {
+        let input =
+            "#define F(x, y) x<y\n#define G -\nF(1, <2) G-F(\n3,\n4)\n\n\n\n\n\n\n\n\n\n5\n";
+        let written = write_preprocessed_string(input);
+        assert_eq!(preprocess(&written), preprocess(input));
+    }
+
+    #[test]
+    fn test_unterminated_conditional() {
+        assert_eq!(
+            error("#ifdef A\n1").message,
+            "unterminated conditional directive"
+        );
+    }
+
+    #[test]
+    fn test_endif_without_if() {
+        assert_eq!(error("1\n#endif").message, "#endif without #if");
+    }
+
+    #[test]
+    fn test_stray_token() {
+        // only an error once it reaches the parser, so that -E can pass it through
+        assert_eq!(
+            preprocess("1 @ 2"),
+            vec![
+                TokenKind::Num(1, IntType::Int),
+                TokenKind::Unknown("@".to_string()),
+                TokenKind::Num(2, IntType::Int),
+                TokenKind::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shared_source_map() {
+        let mut first = Preprocessor::with_source_map(Vec::new(), SourceMap::new());
+        let a = first
+            .preprocess(Path::new("a.c"), "#define A 1\nA".to_string())
+            .unwrap();
+        let mut second = Preprocessor::with_source_map(Vec::new(), first.into_source_map());
+        let b = second
+            .preprocess(Path::new("b.c"), "A".to_string())
+            .unwrap();
+
+        // the macros of a.c are not defined in b.c, and the files of both are kept
+        assert_eq!(*b[0].kind, TokenKind::Ident("A".to_string()));
+        assert_ne!(a[0].pos.file_id, b[0].pos.file_id);
+        let source_map = second.into_source_map();
+        assert_eq!(source_map.get(a[0].pos.file_id).path, Path::new("a.c"));
+        assert_eq!(source_map.get(b[0].pos.file_id).path, Path::new("b.c"));
+    }
+}
@@ -0,0 +1,206 @@
+use std::path::{Path, PathBuf};
+
+use crate::lexer::{Position, Span};
+
+/// All the source files of a compilation, indexed by the `file_id` of `Position`.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+#[derive(Debug)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub input: String,
+    /// Byte offset in `input` where each line starts
+    line_starts: Vec<usize>,
+    /// `#line` directives in this file, ordered by the line they take effect on
+    line_directives: Vec<LineDirective>,
+    /// The `#include` that first read this file, `None` for a main file
+    included_at: Option<Span>,
+}
+
+/// From `line` (0-origin) on, lines are reported as `presumed_line` (1-origin) onwards
+/// and, if `path` is given, as being in that file.
+#[derive(Debug)]
+struct LineDirective {
+    line: usize,
+    presumed_line: usize,
+    path: Option<PathBuf>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a file and return its id.
+    pub fn add(&mut self, path: &Path, input: String) -> usize {
+        let line_starts = std::iter::once(0)
+            .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        self.files.push(SourceFile {
+            path: path.to_path_buf(),
+            input,
+            line_starts,
+            line_directives: Vec::new(),
+            included_at: None,
+        });
+        self.files.len() - 1
+    }
+
+    pub fn get(&self, file_id: usize) -> &SourceFile {
+        &self.files[file_id]
+    }
+
+    /// The text of the 0-origin line `n_line` of a file, without the newline.
+    pub fn line(&self, file_id: usize, n_line: usize) -> Option<&str> {
+        let file = self.get(file_id);
+        let start = *file.line_starts.get(n_line)?;
+        let end = file
+            .line_starts
+            .get(n_line + 1)
+            .map_or(file.input.len(), |next| next - 1);
+        Some(&file.input[start..end])
+    }
+
+    /// The byte offset where the 0-origin line `n_line` of a file starts.
+    pub fn line_start(&self, file_id: usize, n_line: usize) -> usize {
+        self.get(file_id).line_starts[n_line]
+    }
+
+    /// The line and column of the byte `offset` of a file, as the lexer counts them.
+    pub fn position(&self, file_id: usize, offset: usize) -> Position {
+        let file = self.get(file_id);
+        let n_line = file.line_starts.partition_point(|&start| start <= offset) - 1;
+        let line_start = file.line_starts[n_line];
+        Position {
+            n_char: file.input[line_start..offset].chars().count(),
+            n_line,
+            file_id,
+        }
+    }
+
+    /// Record `#line presumed_line "path"` whose next line is `line` of the file.
+    pub fn add_line_directive(
+        &mut self,
+        file_id: usize,
+        line: usize,
+        presumed_line: usize,
+        path: Option<PathBuf>,
+    ) {
+        let directives = &mut self.files[file_id].line_directives;
+        let directive = LineDirective {
+            line,
+            presumed_line,
+            path,
+        };
+        // a file included again from the token cache replays its directives
+        match directives.binary_search_by_key(&line, |directive| directive.line) {
+            Ok(i) => directives[i] = directive,
+            Err(i) => directives.insert(i, directive),
+        }
+    }
+
+    /// Record that a file was read by the `#include` at `span`.
+    pub fn set_included_at(&mut self, file_id: usize, span: Span) {
+        self.files[file_id].included_at = Some(span);
+    }
+
+    /// A key that sorts the spans of a translation unit in the order their text is read:
+    /// the offsets of the `#include`s down to the span's file, then the span's own.
+    pub fn order_key(&self, span: &Span) -> Vec<usize> {
+        let mut key = vec![span.start];
+        let mut file_id = span.file_id;
+        while let Some(at) = &self.get(file_id).included_at {
+            key.push(at.start);
+            file_id = at.file_id;
+        }
+        key.reverse();
+        key
+    }
+
+    /// The file name and 1-origin line number `pos` is reported as, after `#line`.
+    pub fn presumed(&self, pos: &Position) -> (&Path, usize) {
+        let file = self.get(pos.file_id);
+        let applied = file
+            .line_directives
+            .iter()
+            .take_while(|directive| directive.line <= pos.n_line);
+
+        let path = applied
+            .clone()
+            .filter_map(|directive| directive.path.as_deref())
+            .last()
+            .unwrap_or(&file.path);
+        let line = match applied.last() {
+            Some(directive) => directive.presumed_line + (pos.n_line - directive.line),
+            None => pos.n_line + 1,
+        };
+        (path, line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presumed() {
+        let mut source_map = SourceMap::new();
+        let file_id = source_map.add(Path::new("a.c"), "\n".repeat(20));
+        let at = |n_line: usize| Position {
+            n_line,
+            file_id,
+            ..Default::default()
+        };
+
+        source_map.add_line_directive(file_id, 5, 100, None);
+        source_map.add_line_directive(file_id, 10, 1, Some(PathBuf::from("b.c")));
+        source_map.add_line_directive(file_id, 15, 50, None);
+
+        assert_eq!(source_map.presumed(&at(2)), (Path::new("a.c"), 3));
+        assert_eq!(source_map.presumed(&at(6)), (Path::new("a.c"), 101));
+        assert_eq!(source_map.presumed(&at(12)), (Path::new("b.c"), 3));
+        assert_eq!(source_map.presumed(&at(15)), (Path::new("b.c"), 50));
+    }
+
+    #[test]
+    fn test_order_key() {
+        let mut source_map = SourceMap::new();
+        let main = source_map.add(Path::new("a.c"), "x\n#include \"b.h\"\nx\n".to_string());
+        let header = source_map.add(Path::new("b.h"), "x\n".to_string());
+        source_map.set_included_at(header, Span::new(main, 2, 3));
+
+        let mut spans = [
+            Span::new(main, 19, 20),
+            Span::new(header, 0, 1),
+            Span::new(main, 0, 1),
+        ];
+        spans.sort_by_key(|span| source_map.order_key(span));
+        assert_eq!(
+            spans,
+            [
+                Span::new(main, 0, 1),
+                Span::new(header, 0, 1),
+                Span::new(main, 19, 20)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_position() {
+        let mut source_map = SourceMap::new();
+        source_map.add(Path::new("a.c"), String::new());
+        let file_id = source_map.add(Path::new("b.c"), "ab\n\u{e9}x\n".to_string());
+
+        let position = |offset| {
+            let pos = source_map.position(file_id, offset);
+            (pos.n_line, pos.n_char)
+        };
+        assert_eq!(position(1), (0, 1));
+        assert_eq!(position(3), (1, 0));
+        assert_eq!(position(5), (1, 1));
+        assert_eq!(position(7), (2, 0));
+    }
+}
@@ -0,0 +1,161 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::process::Command;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use crate::diagnostic::Diagnostic;
+use crate::error_code::ErrorCode;
+
+/// The assembler, which reads the Intel syntax the generator writes since the output
+/// starts with `.intel_syntax`.
+const ASSEMBLER: &str = "as";
+/// The compiler driver used to link, which knows where the C runtime and the libraries
+/// are, unlike `ld` itself.
+const LINKER: &str = "cc";
+
+/// A file in the temporary directory, removed when it is dropped, so that the
+/// intermediate files are cleaned up on errors too.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Create an empty file with a name of its own ending in `extension`.
+    pub fn new(extension: &str) -> io::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let n = COUNT.fetch_add(1, Ordering::Relaxed);
+            let path =
+                env::temp_dir().join(format!("chimocc-{}-{}.{}", process::id(), n, extension));
+            // a file left by another process with the same id is not reused
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(TempFile { path }),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The name `cc` gives to the output made from `input` when there is no `-o`: the
+/// file name with its extension replaced, in the current directory.
+pub fn default_output(input: &str, extension: &str) -> String {
+    let stem = Path::new(input)
+        .file_stem()
+        .unwrap_or(OsStr::new(input))
+        .to_string_lossy();
+    format!("{}.{}", stem, extension)
+}
+
+/// Write `contents` to `path`, or to stdout if it is `-`.
+pub fn write_output(path: &str, contents: &[u8]) -> Result<(), Diagnostic> {
+    let written = match path {
+        "-" => io::Write::write_all(&mut io::stdout().lock(), contents),
+        _ => fs::write(path, contents),
+    };
+    written.map_err(|err| io_error(&format!("cannot write '{}'", path), err))
+}
+
+/// Let `make` create the file at `path`, or if it is `-`, create a temporary file and
+/// copy it to stdout.
+pub fn make_output(
+    path: &str,
+    make: impl FnOnce(&Path) -> Result<(), Diagnostic>,
+) -> Result<(), Diagnostic> {
+    if path != "-" {
+        return make(Path::new(path));
+    }
+    let temp =
+        TempFile::new("out").map_err(|err| io_error("cannot create a temporary file", err))?;
+    make(temp.path())?;
+    File::open(temp.path())
+        .and_then(|mut file| io::copy(&mut file, &mut io::stdout().lock()))
+        .map(|_| ())
+        .map_err(|err| io_error("cannot write the output", err))
+}
+
+/// Assemble the assembly file `input` into the object file `output`.
+pub fn assemble(input: &Path, output: &Path, verbose: bool) -> Result<(), Diagnostic> {
+    let mut command = Command::new(ASSEMBLER);
+    command.arg("-o").arg(output).arg(input);
+    run(&mut command, verbose)
+}
+
+/// Link the object files `inputs` into the executable `output`.
+pub fn link(inputs: &[&Path], output: &Path, verbose: bool) -> Result<(), Diagnostic> {
+    let mut command = Command::new(LINKER);
+    command.arg("-o").arg(output).args(inputs);
+    run(&mut command, verbose)
+}
+
+/// Run a tool, which reports its own errors on stderr, and fail if it does.
+fn run(command: &mut Command, verbose: bool) -> Result<(), Diagnostic> {
+    let name = command.get_program().to_string_lossy().into_owned();
+    if verbose {
+        let args: Vec<_> = command.get_args().map(OsStr::to_string_lossy).collect();
+        eprintln!("{} {}", name, args.join(" "));
+    }
+    let status = command.status().map_err(|err| {
+        Diagnostic::error(
+            ErrorCode::ToolFailed,
+            None,
+            format!("cannot run '{}': {}", name, err),
+        )
+    })?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Diagnostic::error(
+            ErrorCode::ToolFailed,
+            None,
+            format!("'{}' failed with {}", name, status),
+        ))
+    }
+}
+
+/// A failed file operation, which has no place in the source.
+pub fn io_error(what: &str, err: io::Error) -> Diagnostic {
+    Diagnostic::error(ErrorCode::Io, None, format!("{}: {}", what, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_output() {
+        assert_eq!(default_output("dir/a.c", "s"), "a.s");
+        assert_eq!(default_output("a.b.c", "o"), "a.b.o");
+        assert_eq!(default_output("-", "s"), "-.s");
+    }
+
+    #[test]
+    fn test_temp_file() {
+        let temp = TempFile::new("s").unwrap();
+        let other = TempFile::new("s").unwrap();
+        let path = temp.path().to_path_buf();
+        assert_ne!(path, other.path());
+        assert_eq!(path.extension(), Some(OsStr::new("s")));
+        assert!(path.exists());
+        drop(temp);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::diagnostic::{Diagnostic, Severity, closest_name};
+use crate::error_code::ErrorCode;
+
+/// A kind of warning, turned on with `-W<name>` and off with `-Wno-<name>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Warning {
+    /// `#warning`
+    Cpp,
+    /// Tokens after the operands of a directive
+    ExtraTokens,
+    /// A division or remainder by a constant zero
+    DivByZero,
+    /// A shift by a constant at least the width of the shifted type
+    ShiftCountOverflow,
+    /// A shift by a negative constant
+    ShiftCountNegative,
+    /// `a < b < c` and `a && b || c` without parentheses
+    Parentheses,
+    /// A comparison that converts a possibly negative operand to unsigned
+    SignCompare,
+    /// An input file that is not used, such as an object file with `-c`
+    UnusedInput,
+    /// A `-W` flag naming no warning
+    UnknownWarningOption,
+}
+
+impl Warning {
+    const ALL: [Warning; 9] = [
+        Warning::Cpp,
+        Warning::ExtraTokens,
+        Warning::DivByZero,
+        Warning::ShiftCountOverflow,
+        Warning::ShiftCountNegative,
+        Warning::Parentheses,
+        Warning::SignCompare,
+        Warning::UnusedInput,
+        Warning::UnknownWarningOption,
+    ];
+
+    /// The name in the `-W` flag, which is gcc's where gcc has the warning, and clang's
+    /// where only clang has one.
+    pub fn name(self) -> &'static str {
+        match self {
+            Warning::Cpp => "cpp",
+            Warning::ExtraTokens => "extra-tokens",
+            Warning::DivByZero => "div-by-zero",
+            Warning::ShiftCountOverflow => "shift-count-overflow",
+            Warning::ShiftCountNegative => "shift-count-negative",
+            Warning::Parentheses => "parentheses",
+            Warning::SignCompare => "sign-compare",
+            Warning::UnusedInput => "unused-command-line-argument",
+            Warning::UnknownWarningOption => "unknown-warning-option",
+        }
+    }
+
+    pub fn code(self) -> ErrorCode {
+        match self {
+            Warning::Cpp => ErrorCode::WarningDirective,
+            Warning::ExtraTokens => ErrorCode::ExtraTokens,
+            Warning::DivByZero => ErrorCode::DivByZero,
+            Warning::ShiftCountOverflow => ErrorCode::ShiftCountOverflow,
+            Warning::ShiftCountNegative => ErrorCode::ShiftCountNegative,
+            Warning::Parentheses => ErrorCode::Parentheses,
+            Warning::SignCompare => ErrorCode::SignCompare,
+            Warning::UnusedInput => ErrorCode::UnusedInput,
+            Warning::UnknownWarningOption => ErrorCode::UnknownWarningOption,
+        }
+    }
+
+    /// The group flag that turns this warning on, `None` for the ones that are on by
+    /// default.
+    fn group(self) -> Option<&'static str> {
+        match self {
+            Warning::Parentheses => Some("all"),
+            Warning::SignCompare => Some("extra"),
+            _ => None,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Warning> {
+        Self::ALL.into_iter().find(|warning| warning.name() == name)
+    }
+}
+
+/// Warnings of gcc's that nothing in the language chimocc compiles can set off yet, since
+/// it has no variables, functions, pointers or statements. Their flags are accepted, so
+/// that build scripts written for gcc work, and do nothing.
+const NOT_YET_EMITTED: [&str; 6] = [
+    "unused-variable",
+    "implicit-function-declaration",
+    "int-conversion",
+    "return-type",
+    "shadow",
+    "unreachable-code",
+];
+
+/// Which warnings are reported and which of them are errors, as set by the `-W` flags
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WarningOptions {
+    enabled: HashSet<Warning>,
+    /// `-Werror`
+    all_errors: bool,
+    /// `-Werror=<name>` (true) and `-Wno-error=<name>` (false), which take precedence
+    /// over `-Werror`
+    errors: HashMap<Warning, bool>,
+}
+
+impl Default for WarningOptions {
+    fn default() -> Self {
+        Self {
+            enabled: Warning::ALL
+                .into_iter()
+                .filter(|warning| warning.group().is_none())
+                .collect(),
+            all_errors: false,
+            errors: HashMap::new(),
+        }
+    }
+}
+
+impl WarningOptions {
+    /// Apply a `-W` flag, given without the `-W`: `all`, `extra` (or nothing, its old
+    /// name), `error`, `error=<name>`, `no-error=<name>`, `<name>` or `no-<name>`. Fails
+    /// with the message of the warning to report if the name is unknown, except in
+    /// `no-<name>`, which gcc accepts so that newer warnings can be turned off with older
+    /// compilers. The names in `NOT_YET_EMITTED` are known but stand for no warning.
+    pub fn apply(&mut self, flag: &str) -> Result<(), String> {
+        let flag = if flag.is_empty() { "extra" } else { flag };
+        let warning = |name: &str| match Warning::from_name(name) {
+            Some(warning) => Ok(Some(warning)),
+            None if NOT_YET_EMITTED.contains(&name) => Ok(None),
+            None => {
+                let mut msg = format!("unknown warning option '-W{}'", flag);
+                let names = Warning::ALL.map(Warning::name).into_iter();
+                if let Some(closest) = closest_name(name, names.chain(NOT_YET_EMITTED)) {
+                    let prefix = &flag[..flag.len() - name.len()];
+                    msg = format!("{}; did you mean '-W{}{}'?", msg, prefix, closest);
+                }
+                Err(msg)
+            }
+        };
+
+        match flag {
+            "all" | "extra" => self.enabled.extend(
+                Warning::ALL
+                    .into_iter()
+                    .filter(|warning| warning.group() == Some(flag)),
+            ),
+            "error" => self.all_errors = true,
+            "no-error" => self.all_errors = false,
+            _ => {
+                if let Some(name) = flag.strip_prefix("error=") {
+                    // as in gcc, this turns the warning on as well
+                    if let Some(warning) = warning(name)? {
+                        self.enabled.insert(warning);
+                        self.errors.insert(warning, true);
+                    }
+                } else if let Some(name) = flag.strip_prefix("no-error=") {
+                    if let Some(warning) = warning(name)? {
+                        self.errors.insert(warning, false);
+                    }
+                } else if let Some(name) = flag.strip_prefix("no-") {
+                    if let Some(warning) = Warning::from_name(name) {
+                        self.enabled.remove(&warning);
+                    }
+                } else if let Some(warning) = warning(flag)? {
+                    self.enabled.insert(warning);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `diagnostic` as it is to be reported: `None` if it is a warning that is off, and
+    /// an error if it is a warning made one.
+    pub fn filter(&self, mut diagnostic: Diagnostic) -> Option<Diagnostic> {
+        let Some(warning) = diagnostic.warning else {
+            return Some(diagnostic);
+        };
+        if !self.enabled.contains(&warning) {
+            return None;
+        }
+        if self
+            .errors
+            .get(&warning)
+            .copied()
+            .unwrap_or(self.all_errors)
+        {
+            diagnostic.severity = Severity::Error;
+        }
+        Some(diagnostic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn severity(options: &WarningOptions, warning: Warning) -> Option<Severity> {
+        options
+            .filter(Diagnostic::warning(warning, None, "w"))
+            .map(|diagnostic| diagnostic.severity)
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut options = WarningOptions::default();
+        assert_eq!(
+            severity(&options, Warning::DivByZero),
+            Some(Severity::Warning)
+        );
+        assert_eq!(severity(&options, Warning::Parentheses), None);
+
+        options.apply("all").unwrap();
+        assert_eq!(
+            severity(&options, Warning::Parentheses),
+            Some(Severity::Warning)
+        );
+        assert_eq!(severity(&options, Warning::SignCompare), None);
+
+        options.apply("error").unwrap();
+        options.apply("no-error=cpp").unwrap();
+        options.apply("no-div-by-zero").unwrap();
+        assert_eq!(
+            severity(&options, Warning::Parentheses),
+            Some(Severity::Error)
+        );
+        assert_eq!(severity(&options, Warning::Cpp), Some(Severity::Warning));
+        assert_eq!(severity(&options, Warning::DivByZero), None);
+
+        // a bare -W is the old name of -Wextra
+        let mut extra = WarningOptions::default();
+        extra.apply("").unwrap();
+        assert_eq!(
+            severity(&extra, Warning::SignCompare),
+            Some(Severity::Warning)
+        );
+
+        options.apply("error=sign-compare").unwrap();
+        assert_eq!(
+            severity(&options, Warning::SignCompare),
+            Some(Severity::Error)
+        );
+
+        assert_eq!(options.apply("no-unknown"), Ok(()));
+        assert_eq!(
+            options.apply("unknown"),
+            Err("unknown warning option '-Wunknown'".to_string())
+        );
+        // gcc's warnings that nothing can set off yet are known, and do nothing
+        for flag in ["unused-variable", "no-shadow", "error=return-type"] {
+            let before = options.clone();
+            assert_eq!(options.apply(flag), Ok(()));
+            assert_eq!(options, before);
+        }
+        assert_eq!(
+            options.apply("unused-variables"),
+            Err(
+                "unknown warning option '-Wunused-variables'; did you mean '-Wunused-variable'?"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            options.apply("error=parenthesis"),
+            Err(
+                "unknown warning option '-Werror=parenthesis'; did you mean '-Werror=parentheses'?"
+                    .to_string()
+            )
+        );
+        // errors are not affected
+        assert_eq!(
+            options
+                .filter(Diagnostic::error(ErrorCode::Io, None, "e"))
+                .map(|diagnostic| diagnostic.severity),
+            Some(Severity::Error)
+        );
+    }
+}
@@ -8,9 +8,7 @@ run() {
     input=$1
     
     echo "$input" > tmp.c
-    $COMPILER tmp.c
-
-    cc -o tmp tmp.s
+    $COMPILER -o tmp tmp.c
 
     ./tmp
 }
@@ -30,6 +28,23 @@ assert() {
     fi
 }
 
+assert_error() {
+    input=$1
+    shift
+
+    echo "$input" > tmp.c
+    $COMPILER "$@" tmp.c 2> /dev/null
+    actual="$?"
+
+    # a crash exits with 101
+    if [ "$actual" -ne 1 ]; then
+        echo "Test failed: expected an error for input '$input', got exit status $actual"
+        exit 1
+    else
+        echo "Test passed: got an error for input '$input'"
+    fi
+}
+
 # Test cases
 assert 1 "1"
 assert 0 "0"
@@ -47,4 +62,190 @@ assert 10 "-10 + 20"
 assert 10 "- -10"
 assert 10 "- - +10"
 
+assert 255 "0xff"
+assert 15 "0X0F"
+assert 8 "010"
+assert 5 "0b101"
+assert 42 "42u + 0ul - 0LL"
+assert 1 "4294967296 / 4294967296"
+assert 2 "0xffffffffffff / 0x7fffffffffff"
+
+assert 3 "1 + /* comment */ 2"
+assert 3 "1 + 2 // comment"
+assert 6 "3 * /* multi
+line */ 2"
+assert 12 "1\\
+2"
+
+assert 7 "#define SEVEN 7
+SEVEN"
+assert 9 "#define SQ(x) ((x) * (x))
+SQ(1 + 2)"
+assert 3 "#define SUB(a, b) a - b
+SUB(SUB(5, 1), 1)"
+
+assert 1 "7 % 3"
+assert 1 "1 == 1"
+assert 0 "1 != 1"
+assert 1 "1 < 2"
+assert 0 "2 <= 1"
+assert 1 "2 > 1"
+assert 1 "2 >= 2"
+assert 1 "!0"
+assert 0 "!5"
+assert 255 "~0 & 255"
+assert 6 "3 ^ 5"
+assert 7 "3 | 5"
+assert 16 "1 << 4"
+assert 4 "-16 >> 2 & 7"
+assert 0 "0 && 1 / 0"
+assert 1 "1 || 1 / 0"
+assert 1 "2 && 3"
+assert 5 "0 ? 1 / 0 : 5"
+assert 3 "1 ? 2 ? 3 : 4 : 5"
+
+assert 0 "-1 < 1u"
+assert 1 "-1 > 1u"
+assert 0 "-1 <= 0u"
+assert 1 "-1 >= 0u"
+assert 1 "-1L < 1u"
+assert 0 "-1 < 1ul"
+assert 1 "-1 == 4294967295u"
+assert 1 "(0u - 1) / 2 == 2147483647"
+assert 3 "(0u - 1) % 4"
+assert 1 "(0u - 1) >> 31"
+assert 255 "-1 >> 31 & 255"
+assert 1 "(0ul - 1) >> 63"
+assert 1 "(1 ? -1 : 0u) == 4294967295u"
+assert 0 "2147483647 + 1 > 0"
+
+assert 2 "#define V 2
+#if defined(V) && V >= 2
+V
+#else
+0
+#endif"
+assert 12 "#define CAT(a, b) a ## b
+CAT(1, 2)"
+assert 6 "#define SUM(...) 0 __VA_OPT__(+) __VA_ARGS__
+SUM() + SUM(1 + 5)"
+assert 3 "#define FIRST(x, ...) x
+FIRST(3, 4, 5)"
+assert 1 "#ifdef UNDEFINED
+@
+#elif 1
+1
+#endif"
+assert 7 "#pragma pack(push, 4)
+#line 5
+__LINE__ + 2"
+assert 98 "'b'"
+assert 1 "#include <stdbool.h>
+true"
+assert 3 "__extension__ __attribute__((unused)) 3"
+
+assert_output() {
+    expected=$1
+    shift
+
+    "$@" > /dev/null
+    ./tmp
+    actual="$?"
+    rm -f tmp tmp.s tmp.o
+
+    if [ "$actual" -ne "$expected" ]; then
+        echo "Test failed: expected $expected, got $actual for '$*'"
+        exit 1
+    else
+        echo "Test passed: expected $expected, got $actual for '$*'"
+    fi
+}
+
+echo "3 + 4" > tmp.c
+assert_output 7 eval '$COMPILER -S tmp.c && cc -o tmp tmp.s'
+assert_output 7 eval '$COMPILER -S -o tmp.o.s tmp.c && mv tmp.o.s tmp.s && cc -o tmp tmp.s'
+assert_output 7 eval '$COMPILER -c tmp.c && cc -o tmp tmp.o'
+assert_output 7 eval '$COMPILER -otmp.o -c tmp.c && cc -o tmp tmp.o'
+assert_output 7 eval '$COMPILER -o tmp - < tmp.c'
+assert_output 7 eval '$COMPILER -S -o - tmp.c > tmp.s && cc -o tmp tmp.s'
+assert_output 7 eval '$COMPILER -o - tmp.c > tmp && chmod +x tmp'
+
+printf '.globl data\n.data\ndata: .long 5\n.section .note.GNU-stack,"",@progbits\n' > tmp_data.s
+assert_output 7 eval '$COMPILER -o tmp tmp.c tmp_data.s'
+assert_output 7 eval '$COMPILER -c tmp_data.s && $COMPILER -o tmp tmp_data.o tmp.c'
+assert_output 7 eval '$COMPILER -c tmp.c tmp_data.s && $COMPILER -o tmp tmp.o tmp_data.o'
+assert_output 7 eval '$COMPILER -c -o tmp.o tmp.c tmp_data.o && cc -o tmp tmp.o'
+assert_error "1" -c -o tmp.o tmp_data.s
+rm -f tmp_data.s tmp_data.o
+
+# system headers preprocessed by chimocc can be compiled by cc, except for those with
+# glibc's _FloatN typedefs, which cc has built in
+printf '#include <string.h>\n#include <stdint.h>\n#include <limits.h>\n#include <stddef.h>\n_Static_assert(_Alignof(max_align_t) == 16, "");\nint main(void) { return CHAR_BIT - 1; }\n' > tmp.c
+assert_output 7 eval '$COMPILER -E tmp.c | cc -o tmp -x c -'
+printf '#include <stdio.h>\n#include <stdlib.h>\n#include <math.h>\n' > tmp.c
+if ! $COMPILER -E -o /dev/null tmp.c; then
+    echo "Test failed: cannot preprocess stdio.h, stdlib.h and math.h"
+    exit 1
+fi
+echo "Test passed: stdio.h, stdlib.h and math.h preprocess without errors"
+
+assert_error "1 +"
+assert_error "(1 2"
+assert_error "#error stop"
+assert_error "1 / 0" -Werror
+assert_error "1 < 2 < 3" -Werror=parentheses
+assert_error "(1 2" --diagnostics-format=sarif
+assert_error "	1 +" --color=always
+assert_error "1" -O2
+assert_error "1" --color=sometimes
+assert_error "1" -o
+
+# --apply-fixits rewrites the input files, but not the headers they include
+printf '#inclde <stdbool.h>\n' > tmp_fix.h
+echo '#include "tmp_fix.h"' > tmp.c
+$COMPILER --apply-fixits -o tmp tmp.c 2> /dev/null
+if ! grep -q '^#inclde' tmp_fix.h; then
+    echo "Test failed: --apply-fixits rewrote an included header"
+    exit 1
+fi
+cp tmp_fix.h tmp.c
+$COMPILER --apply-fixits -o tmp tmp.c 2> /dev/null
+if ! grep -q '^#include' tmp.c; then
+    echo "Test failed: --apply-fixits did not rewrite the input file"
+    exit 1
+fi
+echo "Test passed: --apply-fixits rewrites only the input files"
+for input in '#define SET =\n1 SET 1\n' '#define OPEN (1 + 2\nOPEN * 3\n'; do
+    printf "$input" > tmp.c
+    cp tmp.c tmp_fix.h
+    $COMPILER --apply-fixits -o tmp tmp.c 2> /dev/null
+    if ! cmp -s tmp.c tmp_fix.h; then
+        echo "Test failed: --apply-fixits rewrote a macro use in '$input'"
+        exit 1
+    fi
+done
+echo "Test passed: --apply-fixits leaves macro expansions alone"
+rm -f tmp_fix.h tmp
+
+# -v reports how the includes were served with -E too
+echo "#include <stdbool.h>" > tmp.c
+if ! $COMPILER -v -E tmp.c 2>&1 > /dev/null | grep -q '^includes: 1 files read'; then
+    echo "Test failed: -v -E printed no preprocessor statistics"
+    exit 1
+fi
+echo "Test passed: -v -E prints the preprocessor statistics"
+
+# the diagnostics of every stage come out in source order, those of a header at its
+# #include
+printf '#warning late\n' > tmp_order.h
+printf '1 +* 2\n#include "tmp_order.h"\n#warning last\n' > tmp.c
+order=$($COMPILER -S -o /dev/null tmp.c 2>&1 | grep -o '^[a-z]*\[C[0-9]*\]: [^[]*' | tr '\n' '|')
+rm -f tmp_order.h
+expected="error[C0003]: expected an expression but got '*'|warning[C0101]: #warning late |warning[C0101]: #warning last |"
+if [ "$order" != "$expected" ]; then
+    echo "Test failed: diagnostics out of source order: $order"
+    exit 1
+fi
+echo "Test passed: diagnostics are reported in source order"
+
 echo "All tests passed"